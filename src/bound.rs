@@ -0,0 +1,342 @@
+use bevy::prelude::*;
+
+// Lower bound on `Layout::line_crossings`, the number of ordered neighbor pairs whose indices fall
+// in different cache lines.
+//
+// Every pair is either inside one line or crosses lines, so
+//     crossings = total pairs - sum over lines of pairs inside that line.
+// The cells of a line can hold at most as many neighbor pairs as the densest arrangement of that
+// many cells, see `max_internal_pairs`. Replacing every line with that arrangement gives the
+// bound.
+//
+// There is no comparably useful bound for `Layout::heuristic`: it allows up to 62 close indices
+// around each cell, more than the 26 neighbors any cell has, so the same argument gives 0.
+
+/// Ordered neighbor pairs in the Moore neighborhood of a volume of `dims`, not counting a cell with
+/// itself. A single plane (`dims.z == 1`) only has the in-plane neighbors.
+pub fn total_pairs(dims: UVec3) -> usize {
+    let dims = dims.as_u64vec3();
+    let per_axis = 3 * dims - 2;
+    (per_axis.element_product() - dims.element_product()) as usize
+}
+
+/// Coordinate of the cell `rank` steps into a centered run: 0, -1, 1, -2, 2, ...
+fn unrank(rank: usize) -> i32 {
+    match rank % 2 {
+        0 => (rank / 2) as i32,
+        _ => -(rank.div_ceil(2) as i32),
+    }
+}
+
+/// Inverse of [`unrank`].
+fn rank(coordinate: i32) -> usize {
+    match coordinate >= 0 {
+        true => 2 * coordinate as usize,
+        false => 2 * (-coordinate) as usize - 1,
+    }
+}
+
+/// Exhaustive search over the compressed sets, see [`max_internal_pairs`].
+struct Densest {
+    /// Longest run, rows and columns a set may have, the dims sorted largest first.
+    limits: [usize; 3],
+    /// Run length of each line, indexed by the ranks of its other two coordinates.
+    runs: Vec<Vec<usize>>,
+    /// Unordered pairs between two adjacent parallel runs of the given lengths.
+    between: Vec<Vec<usize>>,
+    /// Row and column of the lines next to each line.
+    adjacent: Vec<Vec<Vec<(usize, usize)>>>,
+    best: usize,
+}
+
+impl Densest {
+    fn new(cells: usize, dims: UVec3) -> Self {
+        let mut limits = dims.to_array().map(|dim| (dim as usize).min(cells));
+        limits.sort_unstable_by(|a, b| b.cmp(a));
+        let run = |length: usize| (0..length).map(unrank);
+        let between = (0..=limits[0])
+            .map(|a| {
+                (0..=limits[0])
+                    .map(|b| {
+                        run(a)
+                            .map(|x| run(b).filter(|y| (x - y).abs() <= 1).count())
+                            .sum()
+                    })
+                    .collect()
+            })
+            .collect();
+        let adjacent = (0..limits[1])
+            .map(|row| {
+                (0..limits[2])
+                    .map(|column| {
+                        let (y, z) = (unrank(row), unrank(column));
+                        (-1..=1)
+                            .flat_map(|dy| (-1..=1).map(move |dz| (dy, dz)))
+                            .filter(|&offset| offset != (0, 0))
+                            .map(|(dy, dz)| (rank(y + dy), rank(z + dz)))
+                            .filter(|&(row, column)| row < limits[1] && column < limits[2])
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            limits,
+            runs: vec![vec![0; limits[2]]; limits[1]],
+            between,
+            adjacent,
+            best: 0,
+        }
+    }
+
+    /// Tries every run length for the line at `row` and `column` with `left` cells to place,
+    /// `pairs` being the unordered pairs among the runs placed so far.
+    fn fill(&mut self, row: usize, column: usize, left: usize, pairs: usize) {
+        let [longest, rows, columns] = self.limits;
+        let rows_used = row + (column > 0) as usize;
+        let columns_used = self.runs[0].iter().filter(|&&run| run > 0).count();
+        if left == 0 {
+            // every set has an axis order with the extents sorted, only count that one
+            if self.runs[0][0] >= rows_used && rows_used >= columns_used {
+                self.best = self.best.max(pairs);
+            }
+            return;
+        }
+        // the same order cut on the way: no more rows than the first run is long, no more
+        // columns than rows, and enough cells left for a row per column
+        let first = match row + column {
+            0 => longest,
+            _ => self.runs[0][0],
+        };
+        if row == rows.min(first)
+            || (column == 0 && row > 0 && self.runs[row - 1][0] == 0)
+            || left + rows_used < columns_used
+        {
+            return;
+        }
+        if column == columns.min(first) {
+            return self.fill(row + 1, 0, left, pairs);
+        }
+
+        let above = match row {
+            0 => longest,
+            _ => self.runs[row - 1][column],
+        };
+        let before = match column {
+            0 => longest,
+            _ => self.runs[row][column - 1],
+        };
+        for length in 1..=above.min(before).min(left) {
+            // lines not placed yet are still empty, they count the pair once placed
+            let added = length - 1
+                + self.adjacent[row][column]
+                    .iter()
+                    .map(|&(y, z)| self.between[length][self.runs[y][z]])
+                    .sum::<usize>();
+            self.runs[row][column] = length;
+            self.fill(row, column + 1, left - length, pairs + added);
+            self.runs[row][column] = 0;
+        }
+        // or leave the rest of the row empty
+        if column > 0 {
+            self.fill(row + 1, 0, left, pairs);
+        }
+    }
+}
+
+/// Most ordered neighbor pairs any `cells` cells inside a volume of `dims` can have among
+/// themselves.
+///
+/// Moving the cells of every line along an axis into a run centered on the middle of the volume
+/// never loses a pair: a run has the most pairs inside the line, and two centered runs the most
+/// pairs between adjacent lines (a rearrangement inequality). Repeating this along all three axes
+/// ends in a set whose lines are all centered runs, so numbering the coordinates 0, -1, 1, -2, 2,
+/// ... outward from the middle turns it into a stack of runs that get shorter along both other
+/// axes, a plane partition, and this tries them all. Their number grows exponentially with
+/// `cells`: 32 cells take under a second in a release build, 64 cells don't finish in minutes, so
+/// `cells` is limited to [`MAX_LINE_CELLS`].
+pub fn max_internal_pairs(cells: usize, dims: UVec3) -> usize {
+    assert!(
+        cells <= MAX_LINE_CELLS,
+        "the densest arrangement of {} cells takes too long to find, at most {} are supported",
+        cells,
+        MAX_LINE_CELLS
+    );
+    if cells == 0 {
+        return 0;
+    }
+    let mut densest = Densest::new(cells, dims);
+    densest.fill(0, 0, cells, 0);
+    2 * densest.best
+}
+
+/// Most cells [`max_internal_pairs`] and so [`line_crossings_lower_bound`] accept.
+pub const MAX_LINE_CELLS: usize = 32;
+
+/// Lower bound on the line crossings of any layout of a volume of `dims` with `line` voxels per
+/// cache line.
+///
+/// Finds the densest arrangement of `line` cells, see [`max_internal_pairs`], which limits `line`
+/// to [`MAX_LINE_CELLS`] and takes long enough at that size to compute it once per volume.
+pub fn line_crossings_lower_bound(dims: UVec3, line: usize) -> usize {
+    let volume = dims.element_product() as usize;
+    let full_lines = volume / line;
+    let remainder = volume % line;
+
+    let internal =
+        full_lines * max_internal_pairs(line, dims) + max_internal_pairs(remainder, dims);
+    total_pairs(dims).saturating_sub(internal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Layout, cost::Boundaries, curves::Curve, moves::MoveWeights, stencil::Stencil};
+
+    fn crossings(layout: &Layout, line: usize) -> usize {
+        layout.boundary_cost(&Stencil::for_dims(layout.dims()), &Boundaries::line(line))
+    }
+
+    /// Most pairs among any `cells` cells of a box of `dims`, trying every subset.
+    fn exhaustive_pairs(cells: usize, dims: UVec3) -> usize {
+        fn choose(points: &[IVec3], chosen: &mut Vec<IVec3>, from: usize, left: usize) -> usize {
+            if left == 0 {
+                return 0;
+            }
+            let mut best = 0;
+            for i in from..=points.len() - left {
+                let pairs = chosen
+                    .iter()
+                    .filter(|&&other| (other - points[i]).abs().max_element() <= 1)
+                    .count();
+                chosen.push(points[i]);
+                best = best.max(2 * pairs + choose(points, chosen, i + 1, left - 1));
+                chosen.pop();
+            }
+            best
+        }
+
+        let points: Vec<IVec3> = Layout::new_box(dims, Curve::Linear)
+            .order()
+            .into_iter()
+            .map(|cell| cell.as_ivec3())
+            .collect();
+        choose(&points, &mut Vec::new(), 0, cells)
+    }
+
+    #[test]
+    fn densest_matches_exhaustive_search() {
+        for (dims, max_cells) in [
+            (UVec3::new(3, 3, 3), 6),
+            (UVec3::new(4, 4, 2), 6),
+            (UVec3::new(5, 5, 1), 7),
+        ] {
+            for cells in 1..=max_cells {
+                assert_eq!(
+                    max_internal_pairs(cells, dims),
+                    exhaustive_pairs(cells, dims),
+                    "{} cells in {}",
+                    cells,
+                    dims
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn densest_beats_boxes() {
+        // a 3x3 square missing the two corners of one side beats filling it row by row
+        assert_eq!(max_internal_pairs(7, UVec3::new(6, 6, 1)), 28);
+        // and a 3x3x3 cube with a corner moved to the middle of a face beats the cube
+        assert_eq!(max_internal_pairs(27, UVec3::splat(16)), 2 * 162);
+    }
+
+    #[test]
+    #[should_panic(expected = "takes too long")]
+    fn long_lines_are_refused() {
+        line_crossings_lower_bound(UVec3::splat(16), 2 * MAX_LINE_CELLS);
+    }
+
+    #[test]
+    fn bound_holds_for_the_optimum_of_a_tiny_box() {
+        // every order of a 4x2 plane, the ordered neighbor pairs as indices into `cells`
+        let dims = UVec3::new(4, 2, 1);
+        let cells = Layout::new_box(dims, Curve::Linear).order();
+        let pairs: Vec<(usize, usize)> = (0..cells.len())
+            .flat_map(|a| (0..cells.len()).map(move |b| (a, b)))
+            .filter(|&(a, b)| {
+                let offset = cells[a].as_ivec3() - cells[b].as_ivec3();
+                a != b && offset.abs().max_element() <= 1
+            })
+            .collect();
+        assert_eq!(pairs.len(), total_pairs(dims));
+
+        fn permute(
+            position: &mut Vec<usize>,
+            at: usize,
+            pairs: &[(usize, usize)],
+            best: &mut [usize],
+        ) {
+            if at == position.len() {
+                for (line, best) in best.iter_mut().enumerate().skip(1) {
+                    let crossings = pairs
+                        .iter()
+                        .filter(|&&(a, b)| position[a] / line != position[b] / line)
+                        .count();
+                    *best = (*best).min(crossings);
+                }
+                return;
+            }
+            for i in at..position.len() {
+                position.swap(at, i);
+                permute(position, at + 1, pairs, best);
+                position.swap(at, i);
+            }
+        }
+
+        let mut position: Vec<usize> = (0..cells.len()).collect();
+        let mut optimum = [usize::MAX; 9];
+        permute(&mut position, 0, &pairs, &mut optimum);
+        for (line, &optimum) in optimum.iter().enumerate().skip(1) {
+            let bound = line_crossings_lower_bound(dims, line);
+            assert!(bound <= optimum, "line {}", line);
+        }
+        // two 2x2 squares are both the densest arrangement and a layout
+        assert_eq!(line_crossings_lower_bound(dims, 4), optimum[4]);
+        assert_eq!(line_crossings_lower_bound(dims, 8), 0);
+    }
+
+    #[test]
+    fn bound_holds_for_baselines_and_searched_layouts() {
+        let mut rng = rand::rng();
+        for dims in [UVec3::splat(4), UVec3::splat(8), UVec3::splat(16)] {
+            let mut layouts: Vec<Layout> = [Curve::Linear, Curve::Morton, Curve::Hilbert]
+                .into_iter()
+                .map(|curve| Layout::new_box(dims, curve))
+                .collect();
+
+            // a short greedy search on the line crossings, starting from Morton
+            let mut layout = layouts[1].clone();
+            let mut order = layout.order();
+            let mut best = crossings(&layout, 8);
+            for _ in 0..200 {
+                let mv = MoveWeights::swaps().sample(&order, dims, &mut rng).unwrap();
+                mv.apply_with_order(&mut layout, &mut order);
+                let cost = crossings(&layout, 8);
+                if cost <= best {
+                    best = cost;
+                } else {
+                    mv.inverse().apply_with_order(&mut layout, &mut order);
+                }
+            }
+            layouts.push(layout);
+
+            for line in [4, 8, 16] {
+                let bound = line_crossings_lower_bound(dims, line);
+                for layout in layouts.iter() {
+                    assert!(bound <= crossings(layout, line), "{} line {}", dims, line);
+                }
+            }
+        }
+    }
+}
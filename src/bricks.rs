@@ -0,0 +1,145 @@
+use bevy::prelude::*;
+
+use crate::{LINE_VOXELS, Layout, WIDTH, curves::Curve};
+
+// Sweep over the bricked baselines (`Layout::new_bricked`) to find the best hand-designed
+// brick, as a reference point for the search.
+
+/// Every brick shape that tiles the chunk.
+pub fn brick_shapes() -> Vec<UVec3> {
+    let sides: Vec<u32> = (1..=WIDTH as u32)
+        .filter(|&side| (WIDTH as u32).is_multiple_of(side))
+        .collect();
+    let mut shapes = Vec::new();
+    for &x in sides.iter() {
+        for &y in sides.iter() {
+            for &z in sides.iter() {
+                shapes.push(UVec3::new(x, y, z));
+            }
+        }
+    }
+    shapes
+}
+
+fn parse_curves(arg: Option<&String>) -> Result<Vec<Curve>, String> {
+    let Some(arg) = arg.filter(|arg| arg.as_str() != "all") else {
+        return Ok(Curve::ALL.to_vec());
+    };
+    arg.split(',')
+        .map(|name| Curve::from_name(name).ok_or_else(|| format!("unknown curve {:?}", name)))
+        .collect()
+}
+
+/// `bricks [inner curves] [outer curves] [layout yml]`, curves as a comma separated list or `all`.
+///
+/// Prints the best inner/outer combination of every brick shape, best first, and how the given
+/// layout compares to the best of them.
+pub fn run(args: &[String]) {
+    let (inner, outer) = match (parse_curves(args.first()), parse_curves(args.get(1))) {
+        (Ok(inner), Ok(outer)) => (inner, outer),
+        (Err(err), _) | (_, Err(err)) => {
+            println!("{}", err);
+            let names: Vec<_> = Curve::ALL.iter().map(|curve| curve.name()).collect();
+            println!("usage: bricks [inner curves] [outer curves] [layout yml]");
+            println!("curves: {}", names.join(", "));
+            return;
+        }
+    };
+
+    let mut rows = Vec::new();
+    for brick in brick_shapes() {
+        let mut best: Option<(usize, Curve, Curve)> = None;
+        for &inner in inner.iter() {
+            for &outer in outer.iter() {
+                let heuristic = Layout::new_bricked(brick, inner, outer).heuristic();
+                if best.is_none_or(|(best, _, _)| heuristic < best) {
+                    best = Some((heuristic, inner, outer));
+                }
+            }
+        }
+        let (heuristic, inner, outer) = best.unwrap();
+        let crossings = Layout::new_bricked(brick, inner, outer).line_crossings();
+        rows.push((heuristic, crossings, brick, inner, outer));
+    }
+    rows.sort_by_key(|&(heuristic, ..)| heuristic);
+
+    println!(
+        "{:<10} {:<12} {:<12} {:>10} {:>15}",
+        "brick", "inner", "outer", "heuristic", "line crossings"
+    );
+    for &(heuristic, crossings, brick, inner, outer) in rows.iter() {
+        println!(
+            "{:<10} {:<12} {:<12} {:>10} {:>15}",
+            format!("{}x{}x{}", brick.x, brick.y, brick.z),
+            inner.name(),
+            outer.name(),
+            heuristic,
+            crossings,
+        );
+    }
+
+    let (best, _, brick, inner, outer) = rows[0];
+    println!(
+        "best brick: {}x{}x{}, inner {}, outer {}, heuristic {} ({} voxels per line)",
+        brick.x,
+        brick.y,
+        brick.z,
+        inner.name(),
+        outer.name(),
+        best,
+        LINE_VOXELS
+    );
+    if let Some(name) = args.get(2) {
+        let layout = match Layout::read(name) {
+            Ok(layout) => layout,
+            Err(err) => {
+                println!("could not read {:?}: {}", name, err);
+                return;
+            }
+        };
+        let heuristic = layout.heuristic();
+        println!(
+            "{}: {:?}, {:.2}% vs best brick",
+            name,
+            heuristic,
+            ((heuristic as f32 / best as f32) - 1.0) * 100.0,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bricked_layouts_are_permutations() {
+        assert_eq!(brick_shapes().len(), 5 * 5 * 5);
+        let volume = WIDTH * WIDTH * WIDTH;
+        for brick in [UVec3::ONE, UVec3::new(4, 4, 2), UVec3::new(16, 1, 2)] {
+            let layout = Layout::new_bricked(brick, Curve::Hilbert, Curve::Morton);
+            let mut indices: Vec<usize> = Layout::new_linear()
+                .order()
+                .into_iter()
+                .map(|cell| layout.get(cell.as_ivec3()).unwrap())
+                .collect();
+            indices.sort_unstable();
+            assert_eq!(indices, (0..volume).collect::<Vec<_>>(), "{}", brick);
+        }
+    }
+
+    #[test]
+    fn bricks_are_contiguous_in_inner_order() {
+        let brick = UVec3::new(4, 4, 2);
+        let inner = Layout::new_box(brick, Curve::Linear).order();
+        let order = Layout::new_bricked(brick, Curve::Linear, Curve::Morton).order();
+        for cells in order.chunks(inner.len()) {
+            // every run of brick volume indices fills one brick, in the inner order
+            let corner = cells[0].as_uvec3() / brick * brick;
+            let local: Vec<UVec3> = cells.iter().map(|cell| cell.as_uvec3() - corner).collect();
+            let expected: Vec<UVec3> = inner.iter().map(|cell| cell.as_uvec3()).collect();
+            assert_eq!(local, expected);
+        }
+        // the bricks themselves follow the outer curve, Morton starts along x
+        assert_eq!(order[inner.len()].as_uvec3(), UVec3::new(4, 0, 0));
+    }
+}
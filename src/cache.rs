@@ -0,0 +1,590 @@
+use bevy::platform::collections::HashSet;
+
+use crate::{Layout, Stencil, curves::Curve};
+
+// Cache simulation for cost models that look at actual accesses instead of neighbor pairs.
+
+/// Which line a full set evicts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Replacement {
+    /// Least recently used.
+    #[default]
+    Lru,
+    /// Tree pseudo-LRU, as in most hardware caches. Needs a power of two ways, up to 64.
+    Plru,
+}
+
+/// Geometry of a set-associative cache, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub size: usize,
+    pub line: usize,
+    pub ways: usize,
+    pub replacement: Replacement,
+}
+
+impl Default for CacheConfig {
+    /// A typical L1 data cache: 32 KiB, 64 byte lines, 8 ways.
+    fn default() -> Self {
+        Self {
+            size: 32 * 1024,
+            line: 64,
+            ways: 8,
+            replacement: Replacement::Lru,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn sets(&self) -> usize {
+        (self.size / (self.line * self.ways)).max(1)
+    }
+}
+
+/// Set-associative cache, only tracking which lines are present.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    pub config: CacheConfig,
+    /// Lines in each set. Most recently used first for LRU, by way for PLRU.
+    sets: Vec<Vec<usize>>,
+    /// PLRU tree of each set, node `n` has children `2n` and `2n + 1`, a set bit points right.
+    trees: Vec<u64>,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        assert!(
+            config.line > 0 && config.ways > 0,
+            "line and ways must be positive"
+        );
+        assert!(
+            config.replacement != Replacement::Plru
+                || (config.ways.is_power_of_two() && config.ways <= 64),
+            "PLRU needs a power of two ways, up to 64"
+        );
+        Self {
+            config,
+            sets: vec![Vec::with_capacity(config.ways); config.sets()],
+            trees: vec![0; config.sets()],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Accesses the byte at `address`, returns whether it hit.
+    pub fn access(&mut self, address: usize) -> bool {
+        let hit = self.fill(address);
+        match hit {
+            true => self.hits += 1,
+            false => self.misses += 1,
+        }
+        hit
+    }
+
+    /// Brings the line of `address` in without counting it as an access, e.g. for a prefetch.
+    /// Returns whether it was already cached.
+    pub fn fill(&mut self, address: usize) -> bool {
+        let line = address / self.config.line;
+        let index = line % self.config.sets();
+        let set = &mut self.sets[index];
+        let hit = set.iter().position(|&cached| cached == line);
+
+        match self.config.replacement {
+            Replacement::Lru => match hit {
+                Some(way) => set[..=way].rotate_right(1),
+                None => {
+                    set.insert(0, line);
+                    set.truncate(self.config.ways);
+                }
+            },
+            Replacement::Plru => {
+                let tree = &mut self.trees[index];
+                let way = match hit {
+                    Some(way) => way,
+                    None if set.len() < self.config.ways => {
+                        set.push(line);
+                        set.len() - 1
+                    }
+                    None => {
+                        let way = plru_victim(*tree, self.config.ways);
+                        set[way] = line;
+                        way
+                    }
+                };
+                plru_touch(tree, way, self.config.ways);
+            }
+        }
+        hit.is_some()
+    }
+}
+
+/// Points every node on the path to `way` away from it.
+fn plru_touch(tree: &mut u64, way: usize, ways: usize) {
+    let mut node = 1;
+    let mut half = ways / 2;
+    while half > 0 {
+        let right = way & half != 0;
+        match right {
+            true => *tree &= !(1 << node),
+            false => *tree |= 1 << node,
+        }
+        node = node * 2 + right as usize;
+        half /= 2;
+    }
+}
+
+/// Follows the tree to the way it points at.
+fn plru_victim(tree: u64, ways: usize) -> usize {
+    let mut node = 1;
+    while node < ways {
+        node = node * 2 + (tree >> node & 1) as usize;
+    }
+    node - ways
+}
+
+/// Hardware prefetchers in front of the caches. Lines they fetch in time turn the access into a
+/// hit, so prefetch friendly orders get cheaper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefetcherConfig {
+    /// Fetch the next line after every first level miss, and after the first use of a line
+    /// it fetched, so it keeps up with a sequential walk.
+    pub next_line: bool,
+    /// Streams the stride prefetcher tracks, 0 to disable it.
+    pub streams: usize,
+    /// How many strides ahead a confirmed stream fetches.
+    pub distance: usize,
+}
+
+impl Default for PrefetcherConfig {
+    /// No prefetching.
+    fn default() -> Self {
+        Self {
+            next_line: false,
+            streams: 0,
+            distance: 2,
+        }
+    }
+}
+
+/// Stride prefetcher state, a stream per access pattern it is following.
+#[derive(Debug, Clone)]
+pub struct Prefetcher {
+    pub config: PrefetcherConfig,
+    /// Most recently used first.
+    streams: Vec<Stream>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Stream {
+    line: isize,
+    stride: isize,
+    confirmed: bool,
+}
+
+impl Prefetcher {
+    /// Lines a stream can jump and still be considered the same stream.
+    const WINDOW: isize = 16;
+
+    pub fn new(config: PrefetcherConfig) -> Self {
+        Self {
+            config,
+            streams: Vec::with_capacity(config.streams),
+        }
+    }
+
+    /// Lines to fetch after an access to `line` that missed the first level or was the first
+    /// use of a prefetched line. Like in hardware, hits don't train the prefetcher, so the
+    /// accesses that stay in the first level can't send it chasing strides.
+    pub fn observe(&mut self, line: usize, prefetch: &mut Vec<usize>) {
+        if self.config.next_line {
+            prefetch.push(line + 1);
+        }
+        if self.config.streams == 0 {
+            return;
+        }
+
+        let line = line as isize;
+        let nearest = self
+            .streams
+            .iter()
+            .enumerate()
+            .filter(|(_, stream)| (line - stream.line).abs() <= Self::WINDOW)
+            .min_by_key(|(_, stream)| (line - stream.line).abs())
+            .map(|(index, _)| index);
+        let Some(index) = nearest else {
+            self.streams.insert(
+                0,
+                Stream {
+                    line,
+                    stride: 0,
+                    confirmed: false,
+                },
+            );
+            self.streams.truncate(self.config.streams);
+            return;
+        };
+
+        let mut stream = self.streams.remove(index);
+        let stride = line - stream.line;
+        if stride != 0 {
+            // the same stride twice in a row confirms the stream
+            stream.confirmed = stride == stream.stride;
+            stream.stride = stride;
+            stream.line = line;
+            if stream.confirmed {
+                for ahead in 1..=self.config.distance as isize {
+                    let target = line + stride * ahead;
+                    if target >= 0 {
+                        prefetch.push(target as usize);
+                    }
+                }
+            }
+        }
+        self.streams.insert(0, stream);
+    }
+}
+
+/// Cache levels and the prefetcher in front of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheModel {
+    pub levels: Vec<CacheConfig>,
+    pub prefetcher: PrefetcherConfig,
+}
+
+impl Default for CacheModel {
+    fn default() -> Self {
+        Self {
+            levels: vec![CacheConfig::default()],
+            prefetcher: PrefetcherConfig::default(),
+        }
+    }
+}
+
+/// Levels of caches, each only seeing the misses of the one before.
+#[derive(Debug, Clone)]
+pub struct CacheHierarchy {
+    pub caches: Vec<Cache>,
+    pub prefetcher: Prefetcher,
+    /// Lines prefetched that weren't cached yet.
+    pub prefetches: usize,
+    /// Prefetched first level lines that haven't been used yet.
+    unused: HashSet<usize>,
+    pending: Vec<usize>,
+}
+
+impl CacheHierarchy {
+    pub fn new(model: &CacheModel) -> Self {
+        Self {
+            caches: model
+                .levels
+                .iter()
+                .map(|&config| Cache::new(config))
+                .collect(),
+            prefetcher: Prefetcher::new(model.prefetcher),
+            prefetches: 0,
+            unused: HashSet::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Accesses the byte at `address`, returns the level that hit, `levels` for memory.
+    pub fn access(&mut self, address: usize) -> usize {
+        let level = self
+            .caches
+            .iter_mut()
+            .position(|cache| cache.access(address))
+            .unwrap_or(self.caches.len());
+
+        // prefetches go to every level, in first level lines
+        let Some(first) = self.caches.first() else {
+            return level;
+        };
+        let line_size = first.config.line;
+        let line = address / line_size;
+        if self.unused.remove(&line) || level > 0 {
+            self.prefetcher.observe(line, &mut self.pending);
+        }
+        for line in self.pending.drain(..) {
+            let mut fetched = false;
+            for cache in self.caches.iter_mut() {
+                fetched |= !cache.fill(line * line_size);
+            }
+            if fetched {
+                self.prefetches += 1;
+                self.unused.insert(line);
+            }
+        }
+        level
+    }
+
+    pub fn misses(&self) -> Vec<usize> {
+        self.caches.iter().map(|cache| cache.misses).collect()
+    }
+}
+
+/// Order in which a sweep visits the cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Iteration {
+    /// By index, as a loop over the voxel buffer would.
+    Memory,
+    /// Along a curve over the chunk, e.g. `Curve::Linear` for nested x/z/y loops.
+    Curve(Curve),
+}
+
+impl Iteration {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "memory" => Some(Iteration::Memory),
+            name => Curve::from_name(name).map(Iteration::Curve),
+        }
+    }
+}
+
+/// Parses cache options into a model, returning the remaining arguments.
+///
+/// `size=<bytes>`, `line=<bytes>`, `ways=<n>` and `policy=<lru|plru>` describe the current
+/// level, `then` starts the next one. `prefetch=next|none` switches the next line prefetcher,
+/// `streams=<n>` and `distance=<n>` configure the stride prefetcher.
+pub fn parse_model(args: &[String]) -> Result<(CacheModel, Vec<&String>), String> {
+    let mut model = CacheModel::default();
+    let mut rest = Vec::new();
+    for arg in args {
+        let config = model.levels.last_mut().unwrap();
+        if arg == "then" {
+            model.levels.push(CacheConfig::default());
+            continue;
+        }
+        let Some((key, value)) = arg.split_once('=') else {
+            rest.push(arg);
+            continue;
+        };
+        match (key, value) {
+            ("policy", "lru") => config.replacement = Replacement::Lru,
+            ("policy", "plru") => config.replacement = Replacement::Plru,
+            ("prefetch", "next") => model.prefetcher.next_line = true,
+            ("prefetch", "none") => model.prefetcher.next_line = false,
+            ("policy" | "prefetch", _) => return Err(format!("unknown {} {:?}", key, value)),
+            _ => {
+                let value: usize = value.parse().map_err(|err| format!("{:?}: {}", arg, err))?;
+                match key {
+                    "size" => config.size = value,
+                    "line" => config.line = value,
+                    "ways" => config.ways = value,
+                    "streams" => model.prefetcher.streams = value,
+                    "distance" => model.prefetcher.distance = value,
+                    _ => return Err(format!("unknown option {:?}", key)),
+                }
+            }
+        }
+    }
+    for config in model.levels.iter() {
+        if config.line == 0 || config.ways == 0 {
+            return Err("line and ways must be positive".into());
+        }
+        if config.replacement == Replacement::Plru
+            && (!config.ways.is_power_of_two() || config.ways > 64)
+        {
+            return Err("PLRU needs a power of two ways, up to 64".into());
+        }
+    }
+    Ok((model, rest))
+}
+
+/// Usage of the options [`parse_model`] takes.
+pub const MODEL_USAGE: &str = "[size=<bytes>] [line=<bytes>] [ways=<n>] [policy=lru|plru] \
+                               [then ...] [prefetch=next|none] [streams=<n>] [distance=<n>]";
+
+/// `sweep [iteration] [cache options] [layout yml]...`, see [`parse_model`] for the options.
+///
+/// Prints the misses per level of a Moore stencil sweep over the baselines and the given
+/// layouts, visiting the cells in `iteration` order (`memory` or a curve, default `linear`).
+pub fn run_sweep(args: &[String]) {
+    let usage = format!(
+        "usage: sweep [memory|<curve>] {} [layout yml]...",
+        MODEL_USAGE
+    );
+    let (iteration, args) = match args.first().and_then(|arg| Iteration::from_name(arg)) {
+        Some(iteration) => (iteration, &args[1..]),
+        None => (Iteration::Curve(Curve::Linear), args),
+    };
+    let (model, paths) = match parse_model(args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            println!("{}\n{}", err, usage);
+            return;
+        }
+    };
+
+    let stencil = Stencil::moore();
+    println!("{:?} sweep through {:?}", iteration, model);
+    let mut layouts = vec![
+        ("linear".to_string(), Layout::new_linear()),
+        ("morton".to_string(), Layout::new_morton()),
+        ("hilbert".to_string(), Layout::new_hilbert()),
+    ];
+    for path in paths {
+        match Layout::read(path) {
+            Ok(layout) => layouts.push((path.clone(), layout)),
+            Err(err) => println!("could not read {:?}: {}", path, err),
+        }
+    }
+    for (name, layout) in layouts {
+        let misses = layout.sweep_misses(&stencil, iteration, &model);
+        println!("{}: {:?} misses", name, misses);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::default;
+
+    fn misses(config: CacheConfig, lines: &[usize]) -> usize {
+        let mut cache = Cache::new(config);
+        for &line in lines {
+            cache.access(line * config.line);
+        }
+        cache.misses
+    }
+
+    #[test]
+    #[should_panic(expected = "must be positive")]
+    fn zero_ways_are_refused() {
+        Cache::new(CacheConfig {
+            ways: 0,
+            ..CacheConfig::default()
+        });
+    }
+
+    #[test]
+    fn access_hits_misses_and_evicts() {
+        // two sets of one way, even lines map to the first
+        let mut cache = Cache::new(CacheConfig {
+            size: 2 * 64,
+            line: 64,
+            ways: 1,
+            replacement: Replacement::Lru,
+        });
+        assert!(!cache.access(0));
+        assert!(cache.access(63));
+        assert!(!cache.access(64));
+        assert!(!cache.access(128));
+        assert!(cache.access(64));
+        assert!(!cache.access(0));
+        assert_eq!((cache.hits, cache.misses), (2, 4));
+    }
+
+    #[test]
+    fn lru_and_plru_evict_the_expected_lines() {
+        // one set of 4 ways
+        let lru = CacheConfig {
+            size: 4 * 64,
+            line: 64,
+            ways: 4,
+            replacement: Replacement::Lru,
+        };
+        let plru = CacheConfig {
+            replacement: Replacement::Plru,
+            ..lru
+        };
+
+        // 0 is refreshed before 4 comes in, so both evict 1 instead
+        let refreshed = [0, 1, 2, 3, 0, 4, 0];
+        assert_eq!(misses(lru, &refreshed), 5);
+        assert_eq!(misses(plru, &refreshed), 5);
+
+        // cycling through 5 lines always misses with LRU, PLRU's approximation happens to keep
+        // 1 around once
+        let cycle = [0, 1, 2, 3, 4, 0, 1, 2, 3, 4];
+        assert_eq!(misses(lru, &cycle), 10);
+        assert_eq!(misses(plru, &cycle), 9);
+    }
+
+    #[test]
+    fn hierarchy_only_passes_misses_down() {
+        let l1 = CacheConfig {
+            size: 2 * 64,
+            line: 64,
+            ways: 2,
+            replacement: Replacement::Lru,
+        };
+        let l2 = CacheConfig { size: 8 * 64, ..l1 };
+        let mut hierarchy = CacheHierarchy::new(&CacheModel {
+            levels: vec![l1, l2],
+            ..default()
+        });
+        for _ in 0..3 {
+            for line in 0..4 {
+                hierarchy.access(line * 64);
+            }
+        }
+        // 4 lines thrash the 2 ways of L1, but all fit in L2
+        assert_eq!(hierarchy.misses(), vec![12, 4]);
+    }
+
+    #[test]
+    fn prefetchers_cover_sequential_and_strided_lines() {
+        let walk = |prefetcher: PrefetcherConfig, stride: usize| {
+            let mut hierarchy = CacheHierarchy::new(&CacheModel {
+                levels: vec![CacheConfig::default()],
+                prefetcher,
+            });
+            for line in 0..64 {
+                hierarchy.access(line * stride * 64);
+            }
+            hierarchy.misses()[0]
+        };
+
+        let next_line = PrefetcherConfig {
+            next_line: true,
+            ..default()
+        };
+        let stride = PrefetcherConfig {
+            streams: 4,
+            ..default()
+        };
+        assert_eq!(walk(PrefetcherConfig::default(), 1), 64);
+        // the next line is always fetched ahead, only the first access misses
+        assert_eq!(walk(next_line, 1), 1);
+        assert_eq!(walk(next_line, 3), 64);
+        // the first three accesses train the stream, the rest are fetched ahead of time
+        assert_eq!(walk(stride, 3), 3);
+    }
+
+    #[test]
+    fn sweep_visits_the_volume_of_the_layout() {
+        // the linear curve is the memory order of a linear layout, whatever its size
+        let layout = Layout::new_box(bevy::math::UVec3::new(8, 4, 2), Curve::Linear);
+        let stencil = Stencil::moore();
+        let model = CacheModel {
+            levels: vec![CacheConfig {
+                size: 4 * 64,
+                line: 64,
+                ways: 4,
+                replacement: Replacement::Lru,
+            }],
+            ..default()
+        };
+        assert_eq!(
+            layout.sweep_misses(&stencil, Iteration::Curve(Curve::Linear), &model),
+            layout.sweep_misses(&stencil, Iteration::Memory, &model)
+        );
+    }
+
+    #[test]
+    fn model_rejects_impossible_caches() {
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            parse_model(&args).map(|(model, _)| model)
+        };
+        let model = parse(&["ways=64", "policy=plru", "then", "size=1048576"]).unwrap();
+        assert_eq!(model.levels.len(), 2);
+        assert_eq!(model.levels[0].ways, 64);
+        assert!(parse(&["ways=128", "policy=plru"]).is_err());
+        assert!(parse(&["ways=6", "policy=plru"]).is_err());
+        assert!(parse(&["ways=128"]).is_ok());
+        assert!(parse(&["line=0"]).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use bevy::prelude::*;
+
+use crate::{LINE_VOXELS, Layout, Stencil};
+
+/// What the stencil of a voxel on the chunk surface reads beyond the face.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkBoundary {
+    /// Nothing, neighbors outside the chunk are skipped.
+    #[default]
+    Isolated,
+    /// The neighboring chunk, stored with the same layout in a buffer of its own.
+    Tiled,
+    /// The chunk itself, wrapping around at the faces.
+    Periodic,
+}
+
+impl ChunkBoundary {
+    pub const ALL: [ChunkBoundary; 3] = [
+        ChunkBoundary::Isolated,
+        ChunkBoundary::Tiled,
+        ChunkBoundary::Periodic,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChunkBoundary::Isolated => "isolated",
+            ChunkBoundary::Tiled => "tiled",
+            ChunkBoundary::Periodic => "periodic",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|boundary| boundary.name() == name)
+    }
+}
+
+/// How a layout's chunks sit next to each other, for the costs that look past the chunk faces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunks {
+    pub boundary: ChunkBoundary,
+    /// Extra cost of a line read from another chunk's buffer on top of the line itself, e.g. for
+    /// looking up the buffer and the TLB miss of a separate allocation.
+    pub crossing: usize,
+}
+
+impl Default for Chunks {
+    fn default() -> Self {
+        Self {
+            boundary: ChunkBoundary::Tiled,
+            crossing: 1,
+        }
+    }
+}
+
+/// A neighbor as the chunk boundary resolves it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkNeighbor {
+    /// The neighbor's cell inside its chunk.
+    pub cell: IVec3,
+    pub index: usize,
+    /// Offset of the neighbor's chunk, zero unless it is in another chunk's buffer.
+    pub chunk: IVec3,
+}
+
+impl ChunkNeighbor {
+    /// Cache line of the neighbor, distinct for every buffer.
+    pub fn line(&self) -> (IVec3, usize) {
+        (self.chunk, self.index / LINE_VOXELS)
+    }
+}
+
+/// `chunks [isolated|tiled|periodic]... [crossing=<weight>] <layout yml>...`
+///
+/// Prints the line crossings and the distinct lines read per voxel of each layout with the
+/// given chunk boundaries, by default all of them, to see what the surface voxels add.
+pub fn run(args: &[String]) {
+    let mut boundaries = Vec::new();
+    let mut crossing = Chunks::default().crossing;
+    let mut paths = Vec::new();
+    for arg in args {
+        if let Some(boundary) = ChunkBoundary::from_name(arg) {
+            boundaries.push(boundary);
+        } else if let Some(weight) = arg.strip_prefix("crossing=") {
+            match weight.parse() {
+                Ok(weight) => crossing = weight,
+                Err(_) => {
+                    println!("invalid crossing weight {:?}", weight);
+                    return;
+                }
+            }
+        } else {
+            paths.push(arg);
+        }
+    }
+    if boundaries.is_empty() {
+        boundaries = ChunkBoundary::ALL.to_vec();
+    }
+    if paths.is_empty() {
+        println!("usage: chunks [isolated|tiled|periodic]... [crossing=<weight>] <layout yml>...");
+        return;
+    }
+
+    for path in paths {
+        let layout = match Layout::read(path) {
+            Ok(layout) => layout,
+            Err(err) => {
+                println!("could not read {:?}: {}", path, err);
+                continue;
+            }
+        };
+        let stencil = Stencil::for_dims(layout.dims());
+        println!("{} ({}):", path, layout.size_name());
+        for &boundary in boundaries.iter() {
+            let chunks = Chunks { boundary, crossing };
+            let footprints: usize = layout.chunk_footprints(&stencil, &chunks).iter().sum();
+            println!(
+                "  {:<10} cost {:>7}  footprint {:>7}",
+                boundary.name(),
+                layout.chunk_cost(&stencil, &chunks),
+                footprints
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves::Curve;
+
+    #[test]
+    fn isolated_matches_heuristic() {
+        let layout = Layout::new_morton();
+        let stencil = Stencil::moore();
+        let chunks = Chunks {
+            boundary: ChunkBoundary::Isolated,
+            crossing: 5,
+        };
+        assert_eq!(
+            layout.chunk_cost(&stencil, &chunks),
+            layout.heuristic_with(&stencil)
+        );
+        assert_eq!(
+            layout.chunk_footprints(&stencil, &chunks),
+            layout.footprints(&stencil, LINE_VOXELS)
+        );
+    }
+
+    #[test]
+    fn surface_reads_past_the_faces() {
+        let layout = Layout::new_linear();
+        let stencil = Stencil::moore();
+        let corner = IVec3::ZERO;
+        let count = |boundary| {
+            let chunks = Chunks {
+                boundary,
+                crossing: 1,
+            };
+            layout
+                .chunk_neighbors(corner, layout.dims().as_ivec3(), &stencil, &chunks)
+                .count()
+        };
+        assert_eq!(count(ChunkBoundary::Isolated), 8);
+        assert_eq!(count(ChunkBoundary::Tiled), 27);
+        assert_eq!(count(ChunkBoundary::Periodic), 27);
+
+        // tiled and periodic read the same cells, but tiled pays for the other buffers
+        let cost = |boundary, crossing| layout.chunk_cost(&stencil, &Chunks { boundary, crossing });
+        assert!(cost(ChunkBoundary::Tiled, 0) > cost(ChunkBoundary::Isolated, 0));
+        assert!(cost(ChunkBoundary::Tiled, 1) > cost(ChunkBoundary::Tiled, 0));
+        assert_eq!(
+            cost(ChunkBoundary::Periodic, 0),
+            cost(ChunkBoundary::Periodic, 1)
+        );
+    }
+
+    #[test]
+    fn tiled_cost_depends_on_the_surface_lines() {
+        // no two neighbors are a line apart inside an 8x4x2 box, so every layout costs the same
+        // in isolation, but the lines along the faces differ
+        let dims = UVec3::new(8, 4, 2);
+        let stencil = Stencil::moore();
+        let cost = |curve, boundary| {
+            let chunks = Chunks {
+                boundary,
+                crossing: 1,
+            };
+            Layout::new_box(dims, curve).chunk_cost(&stencil, &chunks)
+        };
+        assert_eq!(cost(Curve::Linear, ChunkBoundary::Isolated), 0);
+        assert_eq!(cost(Curve::Hilbert, ChunkBoundary::Isolated), 0);
+        assert!(
+            cost(Curve::Hilbert, ChunkBoundary::Tiled) < cost(Curve::Linear, ChunkBoundary::Tiled)
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use bevy::prelude::*;
+use std::io::prelude::*;
+
+use crate::Layout;
+
+/// `compose <out yml> <layout yml>...`, innermost layout first.
+///
+/// Nests each layout inside the blocks of the next with `Layout::compose`, e.g.
+/// `compose layouts/layout-composed-16^3.yml layouts/layout-4^3.yml layouts/layout-4^3.yml`.
+/// Saved as `layouts/layout-<run name>-<size>.yml` it is picked up as the search's starting point.
+pub fn run(args: &[String]) {
+    let Some((out, paths)) = args.split_first().filter(|(_, paths)| !paths.is_empty()) else {
+        println!("usage: compose <out yml> <layout yml>...");
+        return;
+    };
+
+    let mut layouts = Vec::new();
+    for path in paths {
+        match Layout::read(path) {
+            Ok(layout) => layouts.push(layout),
+            Err(err) => {
+                println!("could not read {:?}: {}", path, err);
+                return;
+            }
+        }
+    }
+
+    // checked up front, `Layout::compose` would panic
+    let dims = layouts
+        .iter()
+        .fold(UVec3::ONE, |dims, layout| dims * layout.dims());
+    if dims.max_element() > Layout::MAX_DIM {
+        println!("composed layout would be {}, too large", dims);
+        return;
+    }
+
+    let mut layouts = layouts.into_iter();
+    let mut layout = layouts.next().unwrap();
+    for outer in layouts {
+        layout = Layout::compose(&layout, &outer);
+    }
+    println!(
+        "composed {}: heuristic {:?}, line crossings {:?}",
+        layout.size_name(),
+        layout.heuristic(),
+        layout.line_crossings(),
+    );
+
+    let layout_buffer = serde_yml::to_string(&layout).unwrap();
+    let mut layout_file = std::fs::File::create(out).unwrap();
+    layout_file.write_all(layout_buffer.as_bytes()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curves::Curve;
+
+    #[test]
+    fn composition_matches_direct_construction() {
+        let morton = |width| Layout::new_box(UVec3::splat(width), Curve::Morton);
+        assert_eq!(
+            Layout::compose(&morton(2), &morton(2)).order(),
+            morton(4).order()
+        );
+        assert_eq!(
+            Layout::compose_levels(&morton(2), 3).order(),
+            morton(8).order()
+        );
+
+        // rows along x, stacked in linear order, are the linear layout
+        let row = Layout::new_box(UVec3::new(4, 1, 1), Curve::Linear);
+        let rows = Layout::new_box(UVec3::new(1, 4, 4), Curve::Linear);
+        assert_eq!(
+            Layout::compose(&row, &rows).order(),
+            Layout::new_box(UVec3::splat(4), Curve::Linear).order()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "too large")]
+    fn composition_past_the_coordinate_limit_panics() {
+        let row = Layout::new_box(UVec3::new(16, 1, 1), Curve::Linear);
+        let rows = Layout::new_box(UVec3::new(17, 1, 1), Curve::Linear);
+        Layout::compose(&row, &rows);
+    }
+}
@@ -0,0 +1,480 @@
+use std::sync::Arc;
+
+use crate::{
+    LINE_VOXELS, Layout, Stencil, VOXEL_BYTES,
+    cache::{CacheModel, Iteration},
+    chunks::Chunks,
+    trace::Trace,
+};
+
+/// Score of an [`Objective`], lower is better.
+pub trait Score:
+    Copy + PartialOrd + std::fmt::Debug + std::fmt::Display + Send + Sync + 'static
+{
+    fn as_f64(self) -> f64;
+}
+
+impl Score for usize {
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Score for f64 {
+    fn as_f64(self) -> f64 {
+        self
+    }
+}
+
+/// Something the search strategies minimize.
+pub trait Objective: Clone + Send + Sync + 'static {
+    type Score: Score;
+
+    fn evaluate(&self, layout: &Layout) -> Self::Score;
+}
+
+/// What the search minimizes, the counting cost models.
+#[derive(Debug, Clone, Default)]
+pub enum Cost {
+    /// [`Layout::heuristic`].
+    #[default]
+    Heuristic,
+    /// Cache misses of replaying a recorded trace, summed over the levels, see
+    /// [`Trace::misses`]. A prefetcher in the model makes the accesses it covers free.
+    Trace {
+        trace: Arc<Trace>,
+        model: CacheModel,
+    },
+    /// Cache misses of a stencil sweep, summed over the levels, see [`Layout::sweep_misses`].
+    Sweep {
+        stencil: Stencil,
+        iteration: Iteration,
+        model: CacheModel,
+    },
+    /// Neighbor pairs weighted by the memory boundaries between them, see
+    /// [`Layout::boundary_cost`].
+    Boundaries(Boundaries),
+    /// Neighbor pairs under `stencil` in different lines of `line_voxels`, for other stencils and
+    /// line sizes than the heuristic's.
+    Lines {
+        stencil: Stencil,
+        line_voxels: usize,
+    },
+    /// [`Layout::chunk_cost`], counting the reads of the surface voxels from the neighboring
+    /// chunks too.
+    Chunks { stencil: Stencil, chunks: Chunks },
+    /// [`Layout::apron_cost`], for layouts padded with `apron` ghost cells on every face.
+    Apron { stencil: Stencil, apron: u32 },
+    /// Distinct lines of `line_voxels` read by the stencil around each voxel, see
+    /// [`Layout::footprint`], aggregated over the volume.
+    Footprint {
+        stencil: Stencil,
+        line_voxels: usize,
+        aggregate: Aggregate,
+    },
+}
+
+impl Cost {
+    pub fn evaluate(&self, layout: &Layout) -> usize {
+        match self {
+            Cost::Heuristic => layout.heuristic(),
+            Cost::Trace { trace, model } => trace.misses(layout, model).iter().sum(),
+            Cost::Sweep {
+                stencil,
+                iteration,
+                model,
+            } => layout.sweep_misses(stencil, *iteration, model).iter().sum(),
+            Cost::Boundaries(boundaries) => {
+                layout.boundary_cost(&Stencil::for_dims(layout.dims()), boundaries)
+            }
+            Cost::Lines {
+                stencil,
+                line_voxels,
+            } => layout.boundary_cost(stencil, &Boundaries::line(*line_voxels)),
+            Cost::Chunks { stencil, chunks } => layout.chunk_cost(stencil, chunks),
+            Cost::Apron { stencil, apron } => layout.apron_cost(stencil, *apron),
+            Cost::Footprint {
+                stencil,
+                line_voxels,
+                aggregate,
+            } => aggregate.apply(layout.footprints(stencil, *line_voxels)),
+        }
+    }
+}
+
+impl Objective for Cost {
+    type Score = usize;
+
+    fn evaluate(&self, layout: &Layout) -> usize {
+        Cost::evaluate(self, layout)
+    }
+}
+
+/// How per voxel costs are combined into one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Sum,
+    /// The worst voxel, which the search can only improve by fixing all of the worst ones.
+    Max,
+    /// The value at or below which the given percent of the voxels fall.
+    Percentile(u8),
+}
+
+impl Aggregate {
+    /// `sum`, `max` or `p<percent>`, e.g. `p99`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sum" => Some(Aggregate::Sum),
+            "max" => Some(Aggregate::Max),
+            _ => match name.strip_prefix('p')?.parse() {
+                Ok(percent) if percent <= 100 => Some(Aggregate::Percentile(percent)),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn apply(self, mut values: Vec<usize>) -> usize {
+        match self {
+            Aggregate::Sum => values.iter().sum(),
+            Aggregate::Max => values.into_iter().max().unwrap_or(0),
+            Aggregate::Percentile(percent) => {
+                if values.is_empty() {
+                    return 0;
+                }
+                let rank = (values.len() * percent as usize).div_ceil(100).max(1) - 1;
+                *values.select_nth_unstable(rank).1
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Aggregate::Sum => write!(f, "sum"),
+            Aggregate::Max => write!(f, "max"),
+            Aggregate::Percentile(percent) => write!(f, "p{}", percent),
+        }
+    }
+}
+
+/// A continuous cost for the search to follow where the counts are flat: most swaps don't move a
+/// pair across the 32 index threshold, so the heuristic ties and the search can only drift.
+#[derive(Debug, Clone)]
+pub struct Continuous {
+    pub stencil: Stencil,
+    /// Weight of the pairs along each offset of `stencil`, e.g. less for the corners.
+    pub offset_weights: Vec<f64>,
+    /// Weight of `ln(1 + index distance)` of every neighbor pair.
+    pub log_distance: f64,
+    /// Weight of the distinct cache lines each voxel's stencil touches.
+    pub footprint: f64,
+    /// Line size of the footprint, in voxels.
+    pub line_voxels: usize,
+}
+
+impl Continuous {
+    /// Log distance of `stencil`, every offset weighted the same.
+    pub fn new(stencil: Stencil) -> Self {
+        let offset_weights = vec![1.0; stencil.0.len()];
+        Self::weighted(stencil, offset_weights)
+    }
+
+    /// Log distance of `stencil`, the pairs along each offset weighted by `offset_weights`.
+    pub fn weighted(stencil: Stencil, offset_weights: Vec<f64>) -> Self {
+        assert_eq!(
+            offset_weights.len(),
+            stencil.0.len(),
+            "one weight per stencil offset"
+        );
+        Self {
+            stencil,
+            offset_weights,
+            log_distance: 1.0,
+            footprint: 0.0,
+            line_voxels: LINE_VOXELS,
+        }
+    }
+}
+
+impl Objective for Continuous {
+    type Score = f64;
+
+    fn evaluate(&self, layout: &Layout) -> f64 {
+        // the fields are public, so the constructor can't be the only check
+        assert_eq!(self.offset_weights.len(), self.stencil.0.len());
+
+        let mut total = 0.0;
+        for (index, cell) in layout.order().into_iter().enumerate() {
+            let cell = cell.as_ivec3();
+            if self.log_distance != 0.0 {
+                for (&offset, &weight) in self.stencil.0.iter().zip(self.offset_weights.iter()) {
+                    if let Some(neighbor) = layout.get(cell + offset) {
+                        let distance = neighbor.abs_diff(index) as f64;
+                        total += self.log_distance * weight * distance.ln_1p();
+                    }
+                }
+            }
+            if self.footprint != 0.0 {
+                total +=
+                    self.footprint * layout.footprint(cell, &self.stencil, self.line_voxels) as f64;
+            }
+        }
+        total
+    }
+}
+
+/// A memory block, e.g. a cache line or a page, and what a neighbor pair split across two of
+/// them costs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Boundary {
+    /// Size of the block in voxels.
+    pub voxels: usize,
+    pub weight: usize,
+}
+
+/// Block sizes that matter for locality. A pair pays the weight of every level it crosses, so
+/// one crossing a page boundary also pays for the cache line boundary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Boundaries(pub Vec<Boundary>);
+
+impl Default for Boundaries {
+    /// Cache lines, 4 KiB pages and 2 MiB huge pages, a TLB miss weighing about as much as 8
+    /// cache misses.
+    fn default() -> Self {
+        Self(vec![
+            Boundary {
+                voxels: LINE_VOXELS,
+                weight: 1,
+            },
+            Boundary {
+                voxels: 4096 / VOXEL_BYTES,
+                weight: 8,
+            },
+            Boundary {
+                voxels: 2 * 1024 * 1024 / VOXEL_BYTES,
+                weight: 64,
+            },
+        ])
+    }
+}
+
+impl Boundaries {
+    /// Only cache lines, which makes [`Layout::boundary_cost`] the line crossings.
+    pub fn lines() -> Self {
+        Self::line(LINE_VOXELS)
+    }
+
+    /// Only lines of `voxels`, e.g. 64 for 128 byte lines.
+    pub fn line(voxels: usize) -> Self {
+        Self(vec![Boundary { voxels, weight: 1 }])
+    }
+
+    /// Cost of neighbors at indices `a` and `b`.
+    pub fn pair_cost(&self, a: usize, b: usize) -> usize {
+        self.0
+            .iter()
+            .filter(|boundary| a / boundary.voxels != b / boundary.voxels)
+            .map(|boundary| boundary.weight)
+            .sum()
+    }
+}
+
+/// `boundaries [<voxels>:<weight>]... <layout yml>...`
+///
+/// Prints the boundary cost of each layout, with the given block sizes and weights or the
+/// default lines, pages and huge pages. Meant for large volumes, e.g. built with `compose`.
+pub fn run_boundaries(args: &[String]) {
+    let mut boundaries = Vec::new();
+    let mut paths = Vec::new();
+    for arg in args {
+        let Some((voxels, weight)) = arg.split_once(':') else {
+            paths.push(arg);
+            continue;
+        };
+        match (voxels.parse(), weight.parse()) {
+            (Ok(voxels), Ok(weight)) if voxels > 0 => boundaries.push(Boundary { voxels, weight }),
+            _ => {
+                println!("invalid boundary {:?}, expected <voxels>:<weight>", arg);
+                return;
+            }
+        }
+    }
+    let boundaries = match boundaries.is_empty() {
+        true => Boundaries::default(),
+        false => Boundaries(boundaries),
+    };
+    if paths.is_empty() {
+        println!("usage: boundaries [<voxels>:<weight>]... <layout yml>...");
+        return;
+    }
+
+    println!("{:?}", boundaries);
+    for path in paths {
+        match Layout::read(path) {
+            Ok(layout) => {
+                let stencil = Stencil::for_dims(layout.dims());
+                println!(
+                    "{} ({}): {:?}",
+                    path,
+                    layout.size_name(),
+                    layout.boundary_cost(&stencil, &boundaries)
+                );
+            }
+            Err(err) => println!("could not read {:?}: {}", path, err),
+        }
+    }
+}
+
+/// `footprint [sum|max|p<percent>]... <layout yml>...`
+///
+/// Prints the distinct cache lines each layout's stencil reads per voxel, aggregated each given
+/// way, by default the sum, median, 99th percentile and max.
+pub fn run_footprint(args: &[String]) {
+    let mut aggregates = Vec::new();
+    let mut paths = Vec::new();
+    for arg in args {
+        match Aggregate::from_name(arg) {
+            Some(aggregate) => aggregates.push(aggregate),
+            None => paths.push(arg),
+        }
+    }
+    if aggregates.is_empty() {
+        aggregates = vec![
+            Aggregate::Sum,
+            Aggregate::Percentile(50),
+            Aggregate::Percentile(99),
+            Aggregate::Max,
+        ];
+    }
+    if paths.is_empty() {
+        println!("usage: footprint [sum|max|p<percent>]... <layout yml>...");
+        return;
+    }
+
+    for path in paths {
+        match Layout::read(path) {
+            Ok(layout) => {
+                let footprints = layout.footprints(&Stencil::for_dims(layout.dims()), LINE_VOXELS);
+                let values: Vec<String> = aggregates
+                    .iter()
+                    .map(|aggregate| {
+                        format!("{} {}", aggregate, aggregate.apply(footprints.clone()))
+                    })
+                    .collect();
+                println!("{} ({}): {}", path, layout.size_name(), values.join(", "));
+            }
+            Err(err) => println!("could not read {:?}: {}", path, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::*;
+    use crate::curves::Curve;
+
+    #[test]
+    fn aggregates() {
+        let values: Vec<usize> = (1..=100).rev().collect();
+        assert_eq!(Aggregate::Sum.apply(values.clone()), 5050);
+        assert_eq!(Aggregate::Max.apply(values.clone()), 100);
+        assert_eq!(Aggregate::Percentile(50).apply(values.clone()), 50);
+        assert_eq!(Aggregate::Percentile(99).apply(values.clone()), 99);
+        assert_eq!(Aggregate::Percentile(0).apply(values), 1);
+        assert_eq!(Aggregate::from_name("p90"), Some(Aggregate::Percentile(90)));
+        assert_eq!(Aggregate::from_name("p101"), None);
+    }
+
+    #[test]
+    fn footprint_counts_distinct_lines() {
+        // a line is two rows of the linear layout, so three rows straddle two lines per plane
+        let layout = Layout::new_linear();
+        let moore = Stencil::moore();
+        assert_eq!(
+            layout.footprint(IVec3::new(5, 5, 5), &moore, LINE_VOXELS),
+            6
+        );
+        assert_eq!(layout.footprint(IVec3::ZERO, &moore, LINE_VOXELS), 2);
+
+        // with single voxel lines every neighbor is a line of its own
+        let cost = |line_voxels| {
+            Cost::Footprint {
+                stencil: moore.clone(),
+                line_voxels,
+                aggregate: Aggregate::Max,
+            }
+            .evaluate(&layout)
+        };
+        assert_eq!(cost(1), 27);
+        assert_eq!(cost(LINE_VOXELS), 6);
+    }
+
+    #[test]
+    fn apron_only_counts_interior_centers() {
+        let padded = Layout::new_box(UVec3::splat(18), Curve::Morton);
+        assert_eq!(padded.interior(1).count(), 16 * 16 * 16);
+        assert_eq!(Layout::new_linear_2d(18).interior(1).count(), 16 * 16);
+
+        let moore = Stencil::moore();
+        assert_eq!(padded.apron_cost(&moore, 0), padded.heuristic_with(&moore));
+        assert!(padded.apron_cost(&moore, 1) < padded.heuristic_with(&moore));
+    }
+
+    #[test]
+    fn boundaries_add_the_page_crossings() {
+        let boundaries = Boundaries::default();
+        assert_eq!(boundaries.pair_cost(0, LINE_VOXELS - 1), 0);
+        assert_eq!(boundaries.pair_cost(LINE_VOXELS - 1, LINE_VOXELS), 1);
+        assert_eq!(boundaries.pair_cost(2047, 2048), 1 + 8);
+
+        // the 4 KiB page boundary halves the chunk along a plane for both, so they only differ in
+        // the line crossings: two 16x16 planes share 46 * 46 ordered pairs each way
+        for layout in [Layout::new_linear(), Layout::new_morton()] {
+            let lines = Cost::Boundaries(Boundaries::lines()).evaluate(&layout);
+            assert_eq!(lines, layout.line_crossings());
+            let all = Cost::Boundaries(boundaries.clone()).evaluate(&layout);
+            assert_eq!(all, lines + 8 * 2 * 46 * 46);
+        }
+    }
+
+    #[test]
+    fn continuous_breaks_the_ties_of_the_counts() {
+        // two cells next to each other, once per direction
+        let pair = Layout::new_box(UVec3::new(2, 1, 1), Curve::Linear);
+        let log_distance = Continuous::new(Stencil::moore());
+        assert!((log_distance.evaluate(&pair) - 2.0 * 2f64.ln()).abs() < 1e-9);
+
+        // Morton and Hilbert cross as many lines, but Hilbert keeps neighbors closer
+        let linear = Layout::new_linear();
+        let morton = Layout::new_morton();
+        let hilbert = Layout::new_hilbert();
+        assert_eq!(morton.line_crossings(), hilbert.line_crossings());
+        assert!(log_distance.evaluate(&hilbert) < log_distance.evaluate(&morton));
+        assert!(log_distance.evaluate(&morton) < log_distance.evaluate(&linear));
+
+        // with only the footprint weight it is the summed footprint
+        for line_voxels in [1, 8, LINE_VOXELS] {
+            let footprint = Continuous {
+                log_distance: 0.0,
+                footprint: 1.0,
+                line_voxels,
+                ..Continuous::new(Stencil::moore())
+            };
+            let sum = Cost::Footprint {
+                stencil: Stencil::moore(),
+                line_voxels,
+                aggregate: Aggregate::Sum,
+            };
+            assert_eq!(footprint.evaluate(&morton), sum.evaluate(&morton) as f64);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "one weight per stencil offset")]
+    fn continuous_needs_a_weight_per_offset() {
+        Continuous::weighted(Stencil::von_neumann(), vec![1.0; 6]);
+    }
+}
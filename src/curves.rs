@@ -0,0 +1,354 @@
+use bevy::prelude::*;
+
+use crate::{morton::to_morton_index, symmetry::Symmetry};
+
+// Space-filling curve indices used as layout baselines. Each maps a point to a key, and
+// `Layout::from_key` ranks the cells by it, so widths that aren't a power of the curve's base
+// still get contiguous indices.
+
+/// An order of the cells of a box, for the baselines that are parameterized by one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Linear,
+    Snake,
+    Morton,
+    GrayMorton,
+    Hilbert,
+    Peano,
+}
+
+impl Curve {
+    pub const ALL: [Curve; 6] = [
+        Curve::Linear,
+        Curve::Snake,
+        Curve::Morton,
+        Curve::GrayMorton,
+        Curve::Hilbert,
+        Curve::Peano,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Curve::Linear => "linear",
+            Curve::Snake => "snake",
+            Curve::Morton => "morton",
+            Curve::GrayMorton => "gray-morton",
+            Curve::Hilbert => "hilbert",
+            Curve::Peano => "peano",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|curve| curve.name() == name)
+    }
+
+    /// Sort key of `point` inside a box of `dims`. Only the order of the keys matters, the
+    /// curves that need a power of two or three side cover the box and skip the rest.
+    pub fn key(self, dims: UVec3, point: UVec3) -> u64 {
+        match self {
+            Curve::Linear => (point.x + point.z * dims.x + point.y * dims.x * dims.z) as u64,
+            Curve::Snake => snake_index(dims, point),
+            Curve::Morton => to_morton_index(point) as u64,
+            Curve::GrayMorton => gray_rank(to_morton_index(point) as u64),
+            Curve::Hilbert => hilbert_index_3d(dims.max_element().next_power_of_two(), point),
+            Curve::Peano => {
+                let mut side = 1;
+                while side < dims.max_element() {
+                    side *= 3;
+                }
+                peano_index_3d(side, point)
+            }
+        }
+    }
+}
+
+/// Index of `point` along the 2D Hilbert curve covering `side x side`, `side` a power of two.
+pub fn hilbert_index_2d(side: u32, point: UVec2) -> u64 {
+    let (mut x, mut y) = (point.x, point.y);
+    let mut index = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+        index += (s as u64) * (s as u64) * ((3 * rx) ^ ry);
+
+        // rotate the quadrant so the sub-curve starts and ends at the right corners
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    index
+}
+
+/// Index of `point` along the boustrophedon of a box of `dims`: the linear order with every
+/// other row and plane walked backwards, so consecutive cells are always neighbors.
+pub fn snake_index(dims: UVec3, point: UVec3) -> u64 {
+    let z = match point.y % 2 {
+        0 => point.z,
+        _ => dims.z - 1 - point.z,
+    };
+    let row = point.y * dims.z + z;
+    let x = match row % 2 {
+        0 => point.x,
+        _ => dims.x - 1 - point.x,
+    };
+    (x + z * dims.x + point.y * dims.x * dims.z) as u64
+}
+
+/// Position of the Gray code `gray` in the Gray code sequence.
+pub fn gray_rank(gray: u64) -> u64 {
+    let mut rank = gray;
+    let mut shift = 1;
+    while shift < u64::BITS {
+        rank ^= rank >> shift;
+        shift *= 2;
+    }
+    rank
+}
+
+/// Index of `point` along the 3D Hilbert curve covering `side^3`, `side` a power of two.
+///
+/// Skilling's transpose algorithm ("Programming the Hilbert curve", 2004). The curve starts at
+/// the origin and ends at a corner next to it.
+pub fn hilbert_index_3d(side: u32, point: UVec3) -> u64 {
+    let mut axes = point.to_array();
+    let top = side / 2;
+
+    // undo the rotations and reflections of the coarser levels
+    let mut q = top;
+    while q > 1 {
+        let p = q - 1;
+        for i in 0..3 {
+            if axes[i] & q != 0 {
+                axes[0] ^= p;
+            } else {
+                let t = (axes[0] ^ axes[i]) & p;
+                axes[0] ^= t;
+                axes[i] ^= t;
+            }
+        }
+        q /= 2;
+    }
+
+    // gray encode
+    for i in 1..3 {
+        axes[i] ^= axes[i - 1];
+    }
+    let mut t = 0;
+    let mut q = top;
+    while q > 1 {
+        if axes[2] & q != 0 {
+            t ^= q - 1;
+        }
+        q /= 2;
+    }
+    for axis in axes.iter_mut() {
+        *axis ^= t;
+    }
+
+    // interleave the transposed bits, most significant first
+    let mut index = 0;
+    let mut bit = top;
+    while bit > 0 {
+        for axis in axes {
+            index = index << 1 | (axis & bit != 0) as u64;
+        }
+        bit /= 2;
+    }
+    index
+}
+
+/// Key of the 3D Moore curve covering `side^3`, `side` a power of two: the closed variant of the
+/// Hilbert curve, which ends next to where it starts.
+///
+/// The octants are walked in a Gray code cycle, each filled by a Hilbert curve turned so it
+/// enters next to where the previous octant left off.
+pub fn moore_key_3d(side: u32) -> impl Fn(UVec3) -> u64 {
+    let half = (side / 2).max(1);
+    let half_dims = IVec3::splat(half as i32);
+    let octants: [IVec3; 8] = std::array::from_fn(|step| {
+        let gray = step ^ (step >> 1);
+        IVec3::new(gray as i32 & 1, gray as i32 >> 1 & 1, gray as i32 >> 2 & 1) * half as i32
+    });
+
+    // the corners each turned Hilbert curve enters and leaves its octant at
+    let corners: Vec<IVec3> = (0..8)
+        .map(|bits| IVec3::new(bits & 1, bits >> 1 & 1, bits >> 2 & 1) * (half as i32 - 1))
+        .collect();
+    let last = (half as u64).pow(3) - 1;
+    let ends: Vec<(Symmetry, IVec3, IVec3)> = Symmetry::all()
+        .map(|symmetry| {
+            let index = |&corner: &IVec3| {
+                let local = symmetry.apply(corner, half_dims);
+                hilbert_index_3d(half, local.as_uvec3())
+            };
+            let start = *corners.iter().find(|corner| index(corner) == 0).unwrap();
+            let end = *corners.iter().find(|corner| index(corner) == last).unwrap();
+            (symmetry, start, end)
+        })
+        .collect();
+
+    // pick a turn per octant so each curve starts next to where the previous one ended, and the
+    // last ends next to where the first started
+    fn fill(
+        chosen: &mut Vec<(Symmetry, IVec3, IVec3)>,
+        ends: &[(Symmetry, IVec3, IVec3)],
+        octants: &[IVec3; 8],
+    ) -> bool {
+        let touches = |from: usize, to: usize, end: IVec3, start: IVec3| {
+            (octants[to] + start - octants[from] - end)
+                .abs()
+                .element_sum()
+                == 1
+        };
+        let step = chosen.len();
+        if step == octants.len() {
+            return touches(step - 1, 0, chosen[step - 1].2, chosen[0].1);
+        }
+        for &candidate in ends {
+            if step > 0 && !touches(step - 1, step, chosen[step - 1].2, candidate.1) {
+                continue;
+            }
+            chosen.push(candidate);
+            if fill(chosen, ends, octants) {
+                return true;
+            }
+            chosen.pop();
+        }
+        false
+    }
+
+    let mut chosen = Vec::new();
+    assert!(fill(&mut chosen, &ends, &octants), "no closed Moore curve");
+    let per_octant = (half as u64).pow(3);
+
+    move |point| {
+        let octant = point.as_ivec3() / half as i32;
+        let step = octants
+            .iter()
+            .position(|&corner| corner == octant * half as i32)
+            .unwrap();
+        let local = chosen[step]
+            .0
+            .apply(point.as_ivec3() % half as i32, half_dims);
+        step as u64 * per_octant + hilbert_index_3d(half, local.as_uvec3())
+    }
+}
+
+/// Index of `point` along the 3D Peano curve covering `side^3`, `side` a power of three.
+///
+/// Each level splits the cube into 3x3x3 and walks it as a serpentine, so a base 3 digit of a
+/// coordinate is mirrored whenever the digits of the other axes walked so far add up to an odd
+/// number. Mirroring keeps a digit's parity, so the coordinate digits can stand in for the
+/// index digits.
+pub fn peano_index_3d(side: u32, point: UVec3) -> u64 {
+    let point = point.to_array();
+    let mut parity = [0; 3];
+    let mut index = 0;
+    let mut level = side / 3;
+    while level > 0 {
+        for axis in 0..3 {
+            let digit = point[axis] / level % 3;
+            let others = parity.iter().sum::<u32>() - parity[axis];
+            let walked = match others % 2 {
+                0 => digit,
+                _ => 2 - digit,
+            };
+            index = index * 3 + walked as u64;
+            parity[axis] = (parity[axis] + digit) % 2;
+        }
+        level /= 3;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cells of `side^3` sorted by `key`, checking the keys are distinct.
+    fn walk(side: u32, key: impl Fn(UVec3) -> u64) -> Vec<IVec3> {
+        let mut cells: Vec<(u64, IVec3)> = (0..side.pow(3))
+            .map(|i| UVec3::new(i % side, i / side % side, i / (side * side)))
+            .map(|cell| (key(cell), cell.as_ivec3()))
+            .collect();
+        cells.sort_by_key(|&(key, _)| key);
+        assert!(cells.windows(2).all(|pair| pair[0].0 != pair[1].0));
+        cells.into_iter().map(|(_, cell)| cell).collect()
+    }
+
+    /// Cells of `side^2` in the z = 0 plane sorted by `key`, checking the keys are distinct.
+    fn walk_2d(side: u32, key: impl Fn(UVec2) -> u64) -> Vec<IVec3> {
+        let mut cells: Vec<(u64, IVec3)> = (0..side.pow(2))
+            .map(|i| UVec2::new(i % side, i / side))
+            .map(|cell| (key(cell), cell.extend(0).as_ivec3()))
+            .collect();
+        cells.sort_by_key(|&(key, _)| key);
+        assert!(cells.windows(2).all(|pair| pair[0].0 != pair[1].0));
+        cells.into_iter().map(|(_, cell)| cell).collect()
+    }
+
+    fn continuous(cells: &[IVec3]) -> bool {
+        cells
+            .windows(2)
+            .all(|pair| (pair[1] - pair[0]).abs().element_sum() == 1)
+    }
+
+    #[test]
+    fn snake_is_continuous() {
+        assert!(continuous(&walk(16, |cell| snake_index(
+            UVec3::splat(16),
+            cell
+        ))));
+        assert!(continuous(&walk(5, |cell| snake_index(
+            UVec3::splat(5),
+            cell
+        ))));
+    }
+
+    #[test]
+    fn hilbert_is_continuous() {
+        for side in [2, 4, 16] {
+            let cells = walk(side, |cell| hilbert_index_3d(side, cell));
+            assert!(continuous(&cells));
+            assert_eq!(cells[0], IVec3::ZERO);
+        }
+    }
+
+    #[test]
+    fn hilbert_2d_is_continuous() {
+        for side in [2, 4, 16] {
+            let cells = walk_2d(side, |cell| hilbert_index_2d(side, cell));
+            assert!(continuous(&cells));
+            assert_eq!(cells[0], IVec3::ZERO);
+        }
+    }
+
+    #[test]
+    fn moore_is_closed() {
+        for side in [2, 4, 16] {
+            let cells = walk(side, moore_key_3d(side));
+            assert!(continuous(&cells));
+            assert_eq!((cells[cells.len() - 1] - cells[0]).abs().element_sum(), 1);
+        }
+    }
+
+    #[test]
+    fn peano_is_continuous() {
+        for side in [3, 9, 27] {
+            assert!(continuous(&walk(side, |cell| peano_index_3d(side, cell))));
+        }
+    }
+
+    #[test]
+    fn gray_rank_inverts_gray_code() {
+        for rank in 0..4096u64 {
+            assert_eq!(gray_rank(rank ^ (rank >> 1)), rank);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use crate::Layout;
+
+/// `equivalent <layout yml>...`
+///
+/// Groups the layouts into classes that are the same up to cube symmetries and index reversal,
+/// e.g. to deduplicate `layouts/backup` or compare the results of separate runs.
+pub fn run(paths: &[String]) {
+    if paths.is_empty() {
+        println!("usage: equivalent <layout yml>...");
+        return;
+    }
+
+    let mut classes: Vec<(Layout, Vec<&String>)> = Vec::new();
+    for path in paths {
+        let layout = match Layout::read(path) {
+            Ok(layout) => layout.canonicalize(),
+            Err(err) => {
+                println!("could not read {:?}: {}", path, err);
+                continue;
+            }
+        };
+        let class = classes.iter_mut().find(|(canonical, _)| {
+            canonical.0.len() == layout.0.len() && canonical.order() == layout.order()
+        });
+        match class {
+            Some((_, members)) => members.push(path),
+            None => classes.push((layout, vec![path])),
+        }
+    }
+
+    for (index, (canonical, members)) in classes.iter().enumerate() {
+        println!(
+            "class {} ({}, heuristic {:?}):",
+            index,
+            canonical.size_name(),
+            canonical.heuristic()
+        );
+        for member in members {
+            println!("    {}", member);
+        }
+    }
+    println!(
+        "{} layouts in {} classes",
+        classes
+            .iter()
+            .map(|(_, members)| members.len())
+            .sum::<usize>(),
+        classes.len()
+    );
+}
@@ -0,0 +1,472 @@
+use bevy::{math::U8Vec3, prelude::*};
+use serde::Serialize;
+use std::io::prelude::*;
+
+use crate::{
+    Layout,
+    bound::{MAX_LINE_CELLS, max_internal_pairs},
+    symmetry::Symmetry,
+};
+
+// Exact branch and bound over layouts of a tiny `size^3` cube.
+//
+// Indices are handed out in order, so a node of the search is a prefix of the curve order. The
+// cost is the same as `Layout::heuristic`: ordered neighbor pairs whose indices are at least
+// `line` apart.
+//
+// Symmetry breaking: only the layouts whose order of cells is the smallest of its 48 rotated and
+// mirrored copies are searched, so each cell must be the smallest of its orbit under the
+// symmetries that fix the cells before it. Reversing the order keeps the cost too, so the last
+// cell must also be no further out from the center than the first.
+//
+// Lower bound: the pairs between placed and unplaced cells, and among the unplaced cells, that
+// can't end up closer than `line`.
+// - A placed cell is only close to the next few indices, and each of those holds a single cell,
+//   which can't be close to more placed cells than it has placed neighbors.
+// - The unplaced cells fill the remaining indices, so among them only the indices less than a
+//   line apart are close, and no block of a line can have more pairs than the densest
+//   arrangement of that many cells.
+
+/// Nodes between progress messages of the search.
+const PROGRESS: u64 = 1_000_000;
+
+/// Nodes `run` explores unless told otherwise, a few minutes in a release build.
+pub const DEFAULT_MAX_NODES: u64 = 10_000_000;
+
+/// Summary of an exact search, written next to the layout it found.
+#[derive(Debug, Clone, Serialize)]
+pub struct Proof {
+    pub size: usize,
+    pub line: usize,
+    pub cost: usize,
+    /// Lower bound on the optimum: `cost` if the whole tree was explored, otherwise the smallest
+    /// bound of the nodes left open or pruned.
+    pub lower_bound: usize,
+    pub nodes: u64,
+    pub pruned: u64,
+    /// Whether the whole tree was explored, so `cost` is optimal.
+    pub optimal: bool,
+}
+
+pub struct Exact {
+    size: usize,
+    line: usize,
+    points: Vec<IVec3>,
+    neighbors: Vec<Vec<usize>>,
+    /// Each of the 48 symmetries as a permutation of the points.
+    symmetries: Vec<Vec<usize>>,
+    /// Distances to the center along the axes, sorted, the same for a whole orbit.
+    ring: Vec<[i32; 3]>,
+    /// Most unordered close pairs among the last `n` cells of an order, by `n`.
+    close_among: Vec<usize>,
+
+    /// Index assigned to each point, `usize::MAX` if unplaced.
+    assigned: Vec<usize>,
+    order: Vec<usize>,
+
+    best_cost: usize,
+    best_order: Vec<usize>,
+    nodes: u64,
+    pruned: u64,
+    max_nodes: u64,
+    aborted: bool,
+    /// Smallest bound of the nodes that were pruned or left open by the node limit.
+    frontier_bound: usize,
+}
+
+impl Exact {
+    pub fn new(size: usize, line: usize, max_nodes: u64) -> Self {
+        let mut points = Vec::new();
+        for y in 0..size {
+            for z in 0..size {
+                for x in 0..size {
+                    points.push(IVec3::new(x as i32, y as i32, z as i32));
+                }
+            }
+        }
+
+        let neighbors = points
+            .iter()
+            .map(|&point| {
+                points
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &other)| {
+                        other != point && (other - point).abs().max_element() <= 1
+                    })
+                    .map(|(index, _)| index)
+                    .collect()
+            })
+            .collect();
+
+        let dims = IVec3::splat(size as i32);
+        let symmetries = Symmetry::all()
+            .map(|symmetry| {
+                let id = |point: IVec3| point.x + point.z * dims.x + point.y * dims.x * dims.z;
+                points
+                    .iter()
+                    .map(|&point| id(symmetry.apply(point, dims)) as usize)
+                    .collect()
+            })
+            .collect();
+        let ring = points
+            .iter()
+            .map(|&point| {
+                let mut ring = (2 * point - (dims - 1)).abs().to_array();
+                ring.sort();
+                ring
+            })
+            .collect();
+
+        let close_among = (0..=points.len())
+            .map(|cells| close_among(cells, line, UVec3::splat(size as u32)))
+            .collect();
+
+        // the plain linear order is the starting upper bound
+        let linear: Vec<usize> = (0..points.len()).collect();
+        let mut exact = Self {
+            size,
+            line,
+            assigned: vec![usize::MAX; points.len()],
+            order: Vec::with_capacity(points.len()),
+            best_cost: usize::MAX,
+            best_order: linear.clone(),
+            nodes: 0,
+            pruned: 0,
+            max_nodes,
+            aborted: false,
+            frontier_bound: usize::MAX,
+            points,
+            neighbors,
+            symmetries,
+            ring,
+            close_among,
+        };
+        exact.best_cost = exact.cost(&linear);
+        exact
+    }
+
+    /// Cost of a complete curve order.
+    pub fn cost(&self, order: &[usize]) -> usize {
+        let mut index = vec![0; order.len()];
+        for (i, &point) in order.iter().enumerate() {
+            index[point] = i;
+        }
+
+        let mut total = 0;
+        for (point, neighbors) in self.neighbors.iter().enumerate() {
+            for &neighbor in neighbors {
+                if index[point].abs_diff(index[neighbor]) >= self.line {
+                    total += 1;
+                }
+            }
+        }
+        total
+    }
+
+    /// Misses still to come for the current prefix, see the module comment.
+    fn forced(&self) -> usize {
+        let index = self.order.len();
+        let slots = (self.line - 1).min(self.points.len() - index);
+
+        // unordered pairs between placed and unplaced cells, and among the unplaced cells
+        let (mut across, mut among) = (0, 0);
+        // bounds on the close pairs across, from the placed cells and from the slots
+        let mut reachable = 0;
+        let mut by_slot = vec![0; slots];
+        let mut close = vec![0; slots];
+        for point in 0..self.points.len() {
+            if self.assigned[point] != usize::MAX {
+                let unplaced = self.neighbors[point]
+                    .iter()
+                    .filter(|&&neighbor| self.assigned[neighbor] == usize::MAX)
+                    .count();
+                across += unplaced;
+                reachable += unplaced.min((self.assigned[point] + self.line).saturating_sub(index));
+                continue;
+            }
+
+            close.fill(0);
+            for &neighbor in self.neighbors[point].iter() {
+                match self.assigned[neighbor] {
+                    usize::MAX => among += 1,
+                    at => {
+                        let until = (at + self.line).saturating_sub(index).min(slots);
+                        close[..until].iter_mut().for_each(|count| *count += 1);
+                    }
+                }
+            }
+            for (best, &count) in by_slot.iter_mut().zip(close.iter()) {
+                *best = (*best).max(count);
+            }
+        }
+
+        let among = among / 2;
+        let close_across = reachable.min(by_slot.iter().sum());
+        let close_among = among.min(self.close_among[self.points.len() - index]);
+        2 * (across - close_across + among - close_among)
+    }
+
+    pub fn solve(&mut self) -> Proof {
+        // e.g. a line covering the whole cube, nothing can beat the linear order
+        if self.best_cost > 0 {
+            let symmetries: Vec<usize> = (0..self.symmetries.len()).collect();
+            self.branch(0, 0, &symmetries);
+        }
+
+        let lower_bound = match self.aborted {
+            true => self.frontier_bound.min(self.best_cost),
+            false => self.best_cost,
+        };
+        Proof {
+            size: self.size,
+            line: self.line,
+            cost: self.best_cost,
+            lower_bound,
+            nodes: self.nodes,
+            pruned: self.pruned,
+            optimal: !self.aborted,
+        }
+    }
+
+    /// Whether a cell no further out than the first is still left for the last index.
+    fn can_end(&self) -> bool {
+        let Some(&first) = self.order.first() else {
+            return true;
+        };
+        self.order.len() == self.points.len()
+            || (0..self.points.len()).any(|point| {
+                self.assigned[point] == usize::MAX && self.ring[point] <= self.ring[first]
+            })
+    }
+
+    /// Explores the layouts starting with the current prefix, `cost` being the misses among the
+    /// placed cells, `bound` its lower bound and `stabilizer` the symmetries fixing the prefix.
+    fn branch(&mut self, cost: usize, bound: usize, stabilizer: &[usize]) {
+        if self.nodes >= self.max_nodes {
+            self.aborted = true;
+            self.frontier_bound = self.frontier_bound.min(bound);
+            return;
+        }
+        self.nodes += 1;
+        if self.nodes.is_multiple_of(PROGRESS) {
+            println!(
+                "exact: {} nodes, best cost {}, {} pruned",
+                self.nodes, self.best_cost, self.pruned
+            );
+        }
+
+        let index = self.order.len();
+        if index == self.points.len() {
+            if cost < self.best_cost {
+                self.best_cost = cost;
+                self.best_order = self.order.clone();
+            }
+            return;
+        }
+
+        // try the cells with the most placed neighbors first, good layouts show up early that way
+        let mut candidates: Vec<(usize, usize)> = (0..self.points.len())
+            .filter(|&point| self.assigned[point] == usize::MAX)
+            .filter(|&point| {
+                stabilizer
+                    .iter()
+                    .all(|&symmetry| self.symmetries[symmetry][point] >= point)
+            })
+            .map(|point| {
+                let placed = self.neighbors[point]
+                    .iter()
+                    .filter(|&&neighbor| self.assigned[neighbor] != usize::MAX)
+                    .count();
+                (point, placed)
+            })
+            .collect();
+        candidates.sort_by_key(|&(_, placed)| std::cmp::Reverse(placed));
+
+        for (point, _) in candidates {
+            let misses = self.neighbors[point]
+                .iter()
+                .filter(|&&neighbor| {
+                    self.assigned[neighbor] != usize::MAX
+                        && index - self.assigned[neighbor] >= self.line
+                })
+                .count();
+            let cost = cost + 2 * misses;
+
+            self.assigned[point] = index;
+            self.order.push(point);
+
+            let bound = cost + self.forced();
+            if !self.can_end() {
+                // the reversed order is searched instead
+            } else if bound < self.best_cost {
+                let fixed: Vec<usize> = stabilizer
+                    .iter()
+                    .copied()
+                    .filter(|&symmetry| self.symmetries[symmetry][point] == point)
+                    .collect();
+                self.branch(cost, bound, &fixed);
+            } else {
+                self.pruned += 1;
+                self.frontier_bound = self.frontier_bound.min(bound);
+            }
+
+            self.order.pop();
+            self.assigned[point] = usize::MAX;
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        let order: Vec<U8Vec3> = self
+            .best_order
+            .iter()
+            .map(|&point| self.points[point].as_u8vec3())
+            .collect();
+        Layout::from_order(&order)
+    }
+}
+
+/// Most unordered pairs among `cells` cells of a volume of `dims` whose indices are less than
+/// `line` apart, when they take consecutive indices.
+fn close_among(cells: usize, line: usize, dims: UVec3) -> usize {
+    let within: usize = (1..line.min(cells)).map(|distance| cells - distance).sum();
+
+    // a close pair is either inside a block of a line or across two adjacent ones
+    let (blocks, rest) = (cells / line, cells % line);
+    // the densest arrangement of a longer line takes too long to find, every pair may be close
+    let inside = |cells: usize| {
+        let all = cells * cells.saturating_sub(1) / 2;
+        match cells <= MAX_LINE_CELLS {
+            true => all.min(max_internal_pairs(cells, dims) / 2),
+            false => all,
+        }
+    };
+    let across = (blocks + (rest > 0) as usize).saturating_sub(1) * line * (line - 1) / 2;
+    let by_block = blocks * inside(line.min(cells)) + inside(rest) + across;
+
+    within.min(by_block)
+}
+
+/// `exact <size> [line] [max nodes]`: solve a tiny cube exactly and write the layout and its proof.
+///
+/// Every 2^3 cube is certified at once. A 3^3 cube only for lines 1 and 2, and from 14 on, where
+/// the linear order already has no misses; for lines 3 to 13 the bound stays well below the cost,
+/// so the search stops at `max nodes` (default [`DEFAULT_MAX_NODES`]) and the layout is only the
+/// best one found.
+pub fn run(args: &[String]) {
+    let Some(size) = args.first().and_then(|arg| arg.parse::<usize>().ok()) else {
+        println!("usage: exact <size> [line] [max nodes]");
+        return;
+    };
+    let line = args
+        .get(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(crate::LINE_VOXELS)
+        .max(1);
+    let max_nodes = args
+        .get(2)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_MAX_NODES);
+
+    if line >= size * size * size {
+        println!(
+            "every index is within {} of every other, every layout is optimal",
+            line
+        );
+        return;
+    }
+
+    let mut exact = Exact::new(size, line, max_nodes);
+    let proof = exact.solve();
+    println!("exact: {:?}", proof);
+
+    // only a finished search certifies the layout, anything else is just the best one found
+    let kind = match proof.optimal {
+        true => "exact",
+        false => {
+            println!(
+                "NOT proven optimal: cost {} against a lower bound of {} after {} nodes",
+                proof.cost, proof.lower_bound, proof.nodes
+            );
+            "bnb-best"
+        }
+    };
+    let stem = format!("./layouts/layout-{}-line{}-{}^3", kind, line, size);
+    let name = format!("{}.yml", stem);
+    let proof_name = format!("{}.proof.yml", stem);
+    let layout_buffer = serde_yml::to_string(&exact.layout()).unwrap();
+    let proof_buffer = serde_yml::to_string(&proof).unwrap();
+    println!("name: {:?}", name);
+    let mut layout_file = std::fs::File::create(name).unwrap();
+    let mut proof_file = std::fs::File::create(proof_name).unwrap();
+    layout_file.write_all(layout_buffer.as_bytes()).unwrap();
+    proof_file.write_all(proof_buffer.as_bytes()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheapest of all `n!` orders of a cube of `size`.
+    fn brute_force(exact: &Exact) -> usize {
+        fn permute(exact: &Exact, order: &mut Vec<usize>, at: usize, best: &mut usize) {
+            if at == order.len() {
+                *best = (*best).min(exact.cost(order));
+                return;
+            }
+            for i in at..order.len() {
+                order.swap(at, i);
+                permute(exact, order, at + 1, best);
+                order.swap(at, i);
+            }
+        }
+
+        let mut order: Vec<usize> = (0..exact.points.len()).collect();
+        let mut best = usize::MAX;
+        permute(exact, &mut order, 0, &mut best);
+        best
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        for line in 1..=8 {
+            let mut exact = Exact::new(2, line, u64::MAX);
+            let optimum = brute_force(&exact);
+            let proof = exact.solve();
+            assert!(proof.optimal);
+            assert_eq!(proof.cost, optimum, "line {}", line);
+            assert_eq!(proof.lower_bound, optimum, "line {}", line);
+            assert_eq!(exact.cost(&exact.best_order), optimum);
+        }
+    }
+
+    #[test]
+    fn aborted_search_reports_a_valid_bound() {
+        // 3^3 is too large to solve in a test, a longer search brackets the optimum instead
+        let mut longer = Exact::new(3, 4, 10_000);
+        let reference = longer.solve();
+        assert!(reference.lower_bound <= reference.cost);
+        assert_eq!(longer.cost(&longer.best_order), reference.cost);
+
+        let mut exact = Exact::new(3, 4, 20);
+        let proof = exact.solve();
+        assert!(proof.nodes >= 20 && !proof.optimal);
+        assert_eq!(exact.cost(&exact.best_order), proof.cost);
+        assert!(proof.lower_bound <= reference.cost && reference.cost <= proof.cost);
+    }
+
+    #[test]
+    fn whole_cube_in_a_line_returns_early() {
+        let proof = Exact::new(3, 32, 1).solve();
+        assert!(proof.optimal);
+        assert_eq!((proof.cost, proof.lower_bound, proof.nodes), (0, 0, 0));
+    }
+
+    #[test]
+    fn lines_past_the_densest_limit_still_solve() {
+        // the linear order of a 4^3 cube keeps every neighbor within 21 indices
+        let proof = Exact::new(4, MAX_LINE_CELLS + 1, 1).solve();
+        assert!(proof.optimal);
+        assert_eq!(proof.cost, 0);
+    }
+}
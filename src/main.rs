@@ -8,15 +8,40 @@ use std::io::prelude::*;
 
 use serde::{Deserialize, Serialize};
 
+pub mod bound;
+pub mod bricks;
+pub mod cache;
+pub mod chunks;
+pub mod compose;
+pub mod equivalent;
 pub mod controller;
+pub mod cost;
+pub mod curves;
+pub mod exact;
 pub mod morton;
+pub mod moves;
+pub mod pareto;
+pub mod qap;
+pub mod stencil;
+pub mod symmetry;
+pub mod trace;
 
+use cache::{CacheHierarchy, CacheModel, Iteration};
+use chunks::{ChunkBoundary, ChunkNeighbor, Chunks};
 use controller::*;
+use cost::{Boundaries, Cost, Objective, Score};
+use curves::Curve;
 use morton::*;
+use moves::*;
+use stencil::*;
+use symmetry::{Symmetry, SymmetryGroup};
 
 pub const WIDTH: usize = 16;
+/// Voxels per cache line, 64 bytes because we have 2 byte voxels.
+pub const LINE_VOXELS: usize = 32;
+pub const VOXEL_BYTES: usize = 2;
 
-#[derive(Resource, Serialize, Deserialize)]
+#[derive(Resource, Clone, Serialize, Deserialize)]
 pub struct Layout(HashMap<U8Vec3, usize, fnv::FnvBuildHasher>);
 
 pub fn linearize(point: IVec3) -> usize {
@@ -24,6 +49,9 @@ pub fn linearize(point: IVec3) -> usize {
 }
 
 impl Layout {
+    /// Largest extent along any axis, the cells are stored as u8 coordinates.
+    pub const MAX_DIM: u32 = u8::MAX as u32 + 1;
+
     pub fn new_random() -> Self {
         let mut layout = Self(HashMap::with_capacity_and_hasher(WIDTH * WIDTH * WIDTH, default()));
 
@@ -77,17 +105,213 @@ impl Layout {
         layout
     }
 
+    /// Orders `cells` by `key`, e.g. the index along some space-filling curve. Panics if the
+    /// cells are larger than [`Layout::MAX_DIM`].
+    pub fn from_key(cells: impl IntoIterator<Item = UVec3>, key: impl Fn(UVec3) -> u64) -> Self {
+        let mut cells: Vec<UVec3> = cells.into_iter().collect();
+        let dims = cells.iter().fold(UVec3::ZERO, |dims, &cell| dims.max(cell + 1));
+        assert!(
+            dims.max_element() <= Self::MAX_DIM,
+            "layout would be {}, too large",
+            dims
+        );
+        cells.sort_by_key(|&cell| key(cell));
+        let order: Vec<U8Vec3> = cells.into_iter().map(|cell| cell.as_u8vec3()).collect();
+        Self::from_order(&order)
+    }
+
+    /// Cells of the `WIDTH^3` chunk.
+    fn cells() -> impl Iterator<Item = UVec3> {
+        Self::box_cells(UVec3::splat(WIDTH as u32))
+    }
+
+    /// Cells of a box of `dims`, in linear order.
+    fn box_cells(dims: UVec3) -> impl Iterator<Item = UVec3> {
+        (0..dims.y).flat_map(move |y| {
+            (0..dims.z).flat_map(move |z| (0..dims.x).map(move |x| UVec3::new(x, y, z)))
+        })
+    }
+
+    /// Cells of a box of `dims` in `curve` order, e.g. 18^3 for a 16^3 chunk with an apron.
+    pub fn new_box(dims: UVec3, curve: Curve) -> Self {
+        Self::from_key(Self::box_cells(dims), |cell| curve.key(dims, cell))
+    }
+
+    /// Cells of the chunk in `curve` order.
+    pub fn new_curve(curve: Curve) -> Self {
+        Self::new_curve_with(curve, Symmetry::IDENTITY)
+    }
+
+    /// Cells of the chunk in `curve` order, with the axes permuted and mirrored by `axes` first.
+    pub fn new_curve_with(curve: Curve, axes: Symmetry) -> Self {
+        let dims = IVec3::splat(WIDTH as i32);
+        Self::from_key(Self::cells(), |cell| {
+            let cell = axes.apply(cell.as_ivec3(), dims);
+            curve.key(dims.as_uvec3(), cell.as_uvec3())
+        })
+    }
+
+    /// Linear order with another axis order, `Symmetry::IDENTITY` is `new_linear`.
+    pub fn new_linear_with(axes: Symmetry) -> Self {
+        Self::new_curve_with(Curve::Linear, axes)
+    }
+
+    /// Morton order with another bit order, `Symmetry::IDENTITY` is `new_morton`.
+    pub fn new_morton_with(axes: Symmetry) -> Self {
+        Self::new_curve_with(Curve::Morton, axes)
+    }
+
+    /// Boustrophedon order, linear with every other row and plane reversed.
+    pub fn new_snake() -> Self {
+        Self::new_curve(Curve::Snake)
+    }
+
+    /// Morton order walked in Gray code order, so consecutive indices differ in a single bit of
+    /// the Morton code.
+    pub fn new_gray_morton() -> Self {
+        Self::new_curve(Curve::GrayMorton)
+    }
+
+    pub fn new_hilbert() -> Self {
+        Self::new_curve(Curve::Hilbert)
+    }
+
+    /// Closed Hilbert curve.
+    pub fn new_moore() -> Self {
+        let key = curves::moore_key_3d(WIDTH.next_power_of_two() as u32);
+        Self::from_key(Self::cells(), key)
+    }
+
+    /// Peano curve over the smallest `3^k` cube covering the chunk, cut down to the chunk.
+    pub fn new_peano() -> Self {
+        Self::new_curve(Curve::Peano)
+    }
+
+    /// Bricks of `brick_dims` visited in `outer` order, the cells of each brick in `inner`
+    /// order, e.g. linear inside 4x4x2 bricks and Morton across them.
+    pub fn new_bricked(brick_dims: UVec3, inner: Curve, outer: Curve) -> Self {
+        let chunk = UVec3::splat(WIDTH as u32);
+        assert!(
+            chunk % brick_dims == UVec3::ZERO,
+            "{} bricks don't tile the chunk",
+            brick_dims
+        );
+        let grid = chunk / brick_dims;
+
+        let brick = Self::from_key(Self::box_cells(brick_dims), |cell| {
+            inner.key(brick_dims, cell)
+        });
+        let bricks = Self::from_key(Self::box_cells(grid), |cell| outer.key(grid, cell));
+        Self::compose(&brick, &bricks)
+    }
+
+    /// Tiles blocks laid out like `inner` in the order of `outer`: every cell of `outer` becomes
+    /// a block, and the indices run through a whole block before moving on to the next.
+    ///
+    /// Composing the result again nests another level, like a space-filling curve built from
+    /// optimized blocks. Panics if the result would be larger than [`Layout::MAX_DIM`].
+    pub fn compose(inner: &Layout, outer: &Layout) -> Self {
+        let block = inner.dims();
+        let dims = block * outer.dims();
+        assert!(
+            dims.max_element() <= Self::MAX_DIM,
+            "composed layout would be {}, too large",
+            dims
+        );
+        let cells = inner.order();
+        let order: Vec<U8Vec3> = outer
+            .order()
+            .into_iter()
+            .flat_map(|outer_cell| {
+                let corner = outer_cell.as_uvec3() * block;
+                cells.iter().map(move |&cell| corner + cell.as_uvec3())
+            })
+            .map(|cell| cell.as_u8vec3())
+            .collect();
+        Self::from_order(&order)
+    }
+
+    /// `block` nested in itself `levels` times, e.g. a 4^3 block with 2 levels gives 16^3.
+    /// Panics if the result would be larger than [`Layout::MAX_DIM`].
+    pub fn compose_levels(block: &Layout, levels: usize) -> Self {
+        let mut layout = block.clone();
+        for _ in 1..levels {
+            layout = Self::compose(&layout, block);
+        }
+        layout
+    }
+
+    /// Reads a layout saved as YAML.
+    pub fn read(path: &str) -> std::io::Result<Self> {
+        let layout_str = std::fs::read_to_string(path)?;
+        serde_yml::from_str(&layout_str).map_err(std::io::Error::other)
+    }
+
+    /// Cells of a `width x width` 2D layout, which lives in the z = 0 plane.
+    fn cells_2d(width: usize) -> impl Iterator<Item = UVec3> {
+        (0..width as u32).flat_map(move |y| (0..width as u32).map(move |x| UVec3::new(x, y, 0)))
+    }
+
+    pub fn new_linear_2d(width: usize) -> Self {
+        Self::from_key(Self::cells_2d(width), |cell| (cell.x + cell.y * width as u32) as u64)
+    }
+
+    pub fn new_morton_2d(width: usize) -> Self {
+        Self::from_key(Self::cells_2d(width), |cell| to_morton_index_2d(cell.xy()) as u64)
+    }
+
+    pub fn new_hilbert_2d(width: usize) -> Self {
+        let side = width.next_power_of_two() as u32;
+        Self::from_key(Self::cells_2d(width), |cell| {
+            curves::hilbert_index_2d(side, cell.xy())
+        })
+    }
+
     pub fn position(&self, point: IVec3) -> usize {
-        self.0.get(&point.as_u8vec3()).copied().unwrap_or(usize::MAX)
+        self.get(point).unwrap_or(usize::MAX)
+    }
+
+    /// Index of `point`, `None` if it is outside the layout.
+    pub fn get(&self, point: IVec3) -> Option<usize> {
+        if point.cmplt(IVec3::ZERO).any() || point.cmpgt(IVec3::splat(u8::MAX as i32)).any() {
+            return None;
+        }
+        self.0.get(&point.as_u8vec3()).copied()
+    }
+
+    pub fn contains(&self, point: IVec3) -> bool {
+        self.get(point).is_some()
+    }
+
+    /// Extent of the volume the layout covers, `z == 1` for 2D layouts.
+    pub fn dims(&self) -> UVec3 {
+        self.0
+            .keys()
+            .fold(UVec3::ZERO, |dims, point| dims.max(point.as_uvec3() + 1))
+    }
+
+    /// `16^3` for cubes, `64^2` for squares, as used in the layout file names.
+    pub fn size_name(&self) -> String {
+        let dims = self.dims();
+        if dims.z <= 1 && dims.x == dims.y {
+            format!("{}^2", dims.x)
+        } else if dims.x == dims.y && dims.y == dims.z {
+            format!("{}^3", dims.x)
+        } else {
+            format!("{}x{}x{}", dims.x, dims.y, dims.z)
+        }
     }
 
     pub fn heuristic(&self) -> usize {
+        self.heuristic_with(&Stencil::for_dims(self.dims()))
+    }
+
+    pub fn heuristic_with(&self, stencil: &Stencil) -> usize {
         let mut total = 0;
         for (&point, &point_position) in self.0.iter() {
-            for neighbor in Self::neighbors(point.as_ivec3()) {
-                let neighbor_pos = self.position(neighbor);
-                let distance = (neighbor_pos as isize - point_position as isize).abs() as usize;
-                if distance >= 32 { // 64 bytes because we have 2 byte voxels
+            for (_, neighbor_pos) in self.neighbors(point.as_ivec3(), stencil) {
+                let distance = neighbor_pos.abs_diff(point_position);
+                if distance >= LINE_VOXELS {
                     total += 1;
                 }
             }
@@ -96,75 +320,412 @@ impl Layout {
         total
     }
 
-    pub fn in_bounds(point: IVec3) -> bool {
-        point.x >= 0
-            && point.x < WIDTH as i32
-            && point.y >= 0
-            && point.y < WIDTH as i32
-            && point.z >= 0
-            && point.z < WIDTH as i32
+    /// Cells at least `apron` away from the faces, the stencil centers of a layout that stores
+    /// its chunk with an apron of ghost cells copied from the neighbors. 2D layouts have no apron
+    /// along z.
+    pub fn interior(&self, apron: u32) -> impl Iterator<Item = IVec3> + '_ {
+        let dims = self.dims().as_ivec3();
+        let mut min = IVec3::splat(apron as i32);
+        if dims.z <= 1 {
+            min.z = 0;
+        }
+        let max = dims - min;
+        self.0
+            .keys()
+            .map(|cell| cell.as_ivec3())
+            .filter(move |cell| cell.cmpge(min).all() && cell.cmplt(max).all())
+    }
+
+    /// [`Layout::heuristic_with`] of only the stencils centered in the interior, the apron is
+    /// read but never updated.
+    pub fn apron_cost(&self, stencil: &Stencil, apron: u32) -> usize {
+        let mut total = 0;
+        for point in self.interior(apron) {
+            let point_position = self.position(point);
+            for (_, neighbor_pos) in self.neighbors(point, stencil) {
+                if neighbor_pos.abs_diff(point_position) >= LINE_VOXELS {
+                    total += 1;
+                }
+            }
+        }
+
+        total
+    }
+
+    /// Distinct lines of `line_voxels` the stencil around `point` reads.
+    pub fn footprint(&self, point: IVec3, stencil: &Stencil, line_voxels: usize) -> usize {
+        let mut lines: Vec<usize> = self
+            .neighbors(point, stencil)
+            .map(|(_, index)| index / line_voxels)
+            .collect();
+        lines.sort_unstable();
+        lines.dedup();
+        lines.len()
+    }
+
+    /// [`Layout::footprint`] of every cell, in index order.
+    pub fn footprints(&self, stencil: &Stencil, line_voxels: usize) -> Vec<usize> {
+        self.order()
+            .into_iter()
+            .map(|cell| self.footprint(cell.as_ivec3(), stencil, line_voxels))
+            .collect()
     }
 
-    pub fn neighbors(point: IVec3) -> impl Iterator<Item = IVec3> {
-        (-1..=1)
-            .flat_map(move |x| {
-                (-1..=1).flat_map(move |y| (-1..=1).map(move |z| point + IVec3::new(x, y, z)))
+    /// Neighbor pairs whose indices fall in different cache lines.
+    pub fn line_crossings(&self) -> usize {
+        self.boundary_cost(&Stencil::for_dims(self.dims()), &Boundaries::lines())
+    }
+
+    /// Neighbor pairs weighted by the cache line, page and huge page boundaries between them,
+    /// for volumes large enough that page and TLB locality matter too.
+    pub fn boundary_cost(&self, stencil: &Stencil, boundaries: &Boundaries) -> usize {
+        let mut total = 0;
+        for (&point, &point_position) in self.0.iter() {
+            for (_, neighbor_pos) in self.neighbors(point.as_ivec3(), stencil) {
+                total += boundaries.pair_cost(point_position, neighbor_pos);
+            }
+        }
+
+        total
+    }
+
+    /// Misses per cache level of reading `stencil` around every cell, visiting the cells in
+    /// `iteration` order, with a cold cache. An alternative to the heuristic that accounts for
+    /// capacity and associativity.
+    pub fn sweep_misses(
+        &self,
+        stencil: &Stencil,
+        iteration: Iteration,
+        model: &CacheModel,
+    ) -> Vec<usize> {
+        let cells = match iteration {
+            Iteration::Memory => self.order(),
+            Iteration::Curve(curve) => Self::new_box(self.dims(), curve).order(),
+        };
+        let mut caches = CacheHierarchy::new(model);
+        for cell in cells {
+            for (_, index) in self.neighbors(cell.as_ivec3(), stencil) {
+                caches.access(index * VOXEL_BYTES);
+            }
+        }
+        caches.misses()
+    }
+
+    /// Neighbors of `point` under `stencil` that are part of the layout, with their indices.
+    pub fn neighbors<'a>(
+        &'a self,
+        point: IVec3,
+        stencil: &'a Stencil,
+    ) -> impl Iterator<Item = (IVec3, usize)> + 'a {
+        stencil.0.iter().filter_map(move |&offset| {
+            let neighbor = point + offset;
+            self.get(neighbor).map(|index| (neighbor, index))
+        })
+    }
+
+    /// Neighbors of `point` under `stencil` inside a chunk of `dims`, including the ones past the
+    /// faces that `chunks` places in a neighboring chunk or wraps around.
+    pub fn chunk_neighbors<'a>(
+        &'a self,
+        point: IVec3,
+        dims: IVec3,
+        stencil: &'a Stencil,
+        chunks: &'a Chunks,
+    ) -> impl Iterator<Item = ChunkNeighbor> + 'a {
+        stencil.0.iter().filter_map(move |&offset| {
+            let neighbor = point + offset;
+            let chunk = neighbor.div_euclid(dims);
+            let cell = neighbor.rem_euclid(dims);
+            let chunk = match chunks.boundary {
+                ChunkBoundary::Isolated if chunk != IVec3::ZERO => return None,
+                ChunkBoundary::Isolated | ChunkBoundary::Tiled => chunk,
+                ChunkBoundary::Periodic => IVec3::ZERO,
+            };
+            self.get(cell).map(|index| ChunkNeighbor { cell, index, chunk })
+        })
+    }
+
+    /// [`Layout::heuristic_with`] with the neighbors `chunks` adds past the faces. Those are in
+    /// another chunk's buffer, so each distinct line of it the stencil reads costs
+    /// `chunks.crossing` on top of the line, and layouts that keep the cells along the faces in
+    /// few lines pay less.
+    pub fn chunk_cost(&self, stencil: &Stencil, chunks: &Chunks) -> usize {
+        let dims = self.dims().as_ivec3();
+        let mut total = 0;
+        let mut lines = Vec::new();
+        for (&point, &point_position) in self.0.iter() {
+            lines.clear();
+            for neighbor in self.chunk_neighbors(point.as_ivec3(), dims, stencil, chunks) {
+                if neighbor.chunk != IVec3::ZERO {
+                    lines.push(neighbor.line());
+                } else if neighbor.index.abs_diff(point_position) >= LINE_VOXELS {
+                    total += 1;
+                }
+            }
+            lines.sort_unstable_by_key(|&(chunk, line)| (chunk.to_array(), line));
+            lines.dedup();
+            total += lines.len() * (1 + chunks.crossing);
+        }
+
+        total
+    }
+
+    /// [`Layout::footprints`] with the neighbors `chunks` adds past the faces, counting the
+    /// lines of each buffer separately.
+    pub fn chunk_footprints(&self, stencil: &Stencil, chunks: &Chunks) -> Vec<usize> {
+        let dims = self.dims().as_ivec3();
+        self.order()
+            .into_iter()
+            .map(|cell| {
+                let mut lines: Vec<(IVec3, usize)> = self
+                    .chunk_neighbors(cell.as_ivec3(), dims, stencil, chunks)
+                    .map(|neighbor| neighbor.line())
+                    .collect();
+                lines.sort_unstable_by_key(|&(chunk, line)| (chunk.to_array(), line));
+                lines.dedup();
+                lines.len()
             })
-            // .filter(move |neighbor| *neighbor != point)
-            .filter(|neighbor| Self::in_bounds(*neighbor))
+            .collect()
     }
 
     pub fn swap(&mut self, a: IVec3, b: IVec3) {
-        assert!(Self::in_bounds(a) && Self::in_bounds(b));
+        assert!(self.contains(a) && self.contains(b));
         let [a_pos, b_pos] = self.0.get_many_mut([&a.as_u8vec3(), &b.as_u8vec3()]);
         std::mem::swap(a_pos.unwrap(), b_pos.unwrap());
     }
+
+    /// Reassigns every cell's index through `f`, which must be a permutation of the indices.
+    pub fn remap(&mut self, f: impl Fn(usize) -> usize) {
+        for index in self.0.values_mut() {
+            *index = f(*index);
+        }
+    }
+
+    /// Sets the index of the cells in `order[range]` to their position in `order`, which must
+    /// hold the same cells as the layout.
+    pub fn reindex(&mut self, order: &[U8Vec3], range: std::ops::RangeInclusive<usize>) {
+        for index in range {
+            *self.0.get_mut(&order[index]).expect("cell outside the layout") = index;
+        }
+    }
+
+    /// Builds a layout from its curve order, the inverse of [`Layout::order`].
+    pub fn from_order(order: &[U8Vec3]) -> Self {
+        Self(order.iter().enumerate().map(|(index, &point)| (point, index)).collect())
+    }
+
+    /// The layout moved by `symmetry`, with the indices reversed if `reverse`. Both keep the
+    /// cost the same.
+    pub fn transformed(&self, symmetry: Symmetry, reverse: bool) -> Self {
+        let dims = self.dims().as_ivec3();
+        let mut order: Vec<U8Vec3> = self
+            .order()
+            .iter()
+            .map(|cell| symmetry.apply(cell.as_ivec3(), dims).as_u8vec3())
+            .collect();
+        if reverse {
+            order.reverse();
+        }
+        Self::from_order(&order)
+    }
+
+    /// A unique representative of the layouts equivalent to this one under the cube symmetries
+    /// and index reversal: the one whose cells, in index order, come first lexicographically.
+    pub fn canonicalize(&self) -> Self {
+        let dims = self.dims().as_ivec3();
+        let order: Vec<[u8; 3]> = self.order().iter().map(|cell| cell.to_array()).collect();
+
+        let mut best: Option<Vec<[u8; 3]>> = None;
+        for symmetry in Symmetry::all().filter(|symmetry| symmetry.apply_dims(dims) == dims) {
+            let mut candidate: Vec<[u8; 3]> = order
+                .iter()
+                .map(|&cell| {
+                    let cell = IVec3::from_array(cell.map(|c| c as i32));
+                    symmetry.apply(cell, dims).as_u8vec3().to_array()
+                })
+                .collect();
+            for reverse in [false, true] {
+                if reverse {
+                    candidate.reverse();
+                }
+                if best.as_ref().is_none_or(|best| candidate < *best) {
+                    best = Some(candidate.clone());
+                }
+            }
+        }
+
+        let order: Vec<U8Vec3> = best.unwrap().into_iter().map(U8Vec3::from_array).collect();
+        Self::from_order(&order)
+    }
+
+    /// Whether `other` is this layout up to cube symmetries and index reversal.
+    pub fn equivalent(&self, other: &Layout) -> bool {
+        self.0.len() == other.0.len()
+            && self.dims() == other.dims()
+            && self.canonicalize().order() == other.canonicalize().order()
+    }
+
+    /// The inverse permutation: the cell at each index, in curve order.
+    pub fn order(&self) -> Vec<U8Vec3> {
+        let mut order = vec![U8Vec3::ZERO; self.0.len()];
+        for (&point, &index) in self.0.iter() {
+            order[index] = point;
+        }
+        order
+    }
 }
 
 use rand::{Rng, RngCore};
 
-fn compare_bases() {
-    let linear = Layout::new_linear();
-    let morton = Layout::new_morton();
+/// Every 3D baseline, by name.
+fn named_baselines() -> Vec<(&'static str, Layout)> {
+    vec![
+        ("linear", Layout::new_linear()),
+        ("snake", Layout::new_snake()),
+        ("morton", Layout::new_morton()),
+        ("gray morton", Layout::new_gray_morton()),
+        ("hilbert", Layout::new_hilbert()),
+        ("moore", Layout::new_moore()),
+        ("peano", Layout::new_peano()),
+    ]
+}
+
+/// Scores the baselines with the fixed measures and `cost`, the objective of the search.
+fn compare_bases<O: Objective>(cost: &O) {
+    let baselines = named_baselines();
+    let linear_heuristic = baselines[0].1.heuristic();
+    let linear_cost = cost.evaluate(&baselines[0].1).as_f64();
+
+    let boundaries = Boundaries::default();
+    println!(
+        "{:<12} {:>10} {:>10} {:>15} {:>11} {:>12} {:>10}",
+        "baseline", "heuristic", "vs linear", "line crossings", "boundaries", "cost", "vs linear"
+    );
+    for (name, layout) in baselines.iter() {
+        let heuristic = layout.heuristic();
+        let score = cost.evaluate(layout);
+        println!(
+            "{:<12} {:>10} {:>9.2}% {:>15} {:>11} {:>12.0} {:>9.2}%",
+            name,
+            heuristic,
+            ((heuristic as f32 / linear_heuristic as f32) - 1.0) * 100.0,
+            layout.line_crossings(),
+            layout.boundary_cost(&Stencil::moore(), &boundaries),
+            score,
+            ((score.as_f64() / linear_cost) - 1.0) * 100.0,
+        );
+    }
+
+    // the Moore stencil is the same along every axis, the axis order only matters for stencils
+    // that aren't, e.g. a sweep along one axis
+    for curve in [Curve::Linear, Curve::Morton] {
+        let variants: Vec<(Symmetry, Layout)> = Symmetry::all()
+            .map(|axes| (axes, Layout::new_curve_with(curve, axes)))
+            .collect();
+        for (stencil_name, stencil) in [
+            ("x axis", Stencil::axis(IVec3::X)),
+            ("y axis", Stencil::axis(IVec3::Y)),
+        ] {
+            let scores: Vec<(usize, Symmetry)> = variants
+                .iter()
+                .map(|(axes, layout)| (layout.heuristic_with(&stencil), *axes))
+                .collect();
+            let (best, best_axes) = scores.iter().min_by_key(|(score, _)| *score).unwrap();
+            let (worst, worst_axes) = scores.iter().max_by_key(|(score, _)| *score).unwrap();
+            println!(
+                "{} axis orders, {} stencil: best {} {:?}, worst {} {:?}",
+                curve.name(),
+                stencil_name,
+                best_axes,
+                best,
+                worst_axes,
+                worst,
+            );
+        }
+    }
 
-    let linear_heuristic = linear.heuristic();
-    let morton_heuristic = morton.heuristic();
+    const WIDTH_2D: usize = 64;
     println!(
-        "linear: {:?}, morton: {:?}, {:?}%",
-        linear_heuristic,
-        morton_heuristic,
-        ((morton_heuristic as f32 / linear_heuristic as f32) - 1.0) * 100.0,
+        "{}^2: row-major: {:?}, morton: {:?}, hilbert: {:?}",
+        WIDTH_2D,
+        Layout::new_linear_2d(WIDTH_2D).heuristic(),
+        Layout::new_morton_2d(WIDTH_2D).heuristic(),
+        Layout::new_hilbert_2d(WIDTH_2D).heuristic(),
     );
 }
 
+/// The linear and morton layouts of the same size as `layout`, to compare the search against.
+fn baselines(layout: &Layout) -> (Layout, Layout) {
+    let dims = layout.dims();
+    if dims.z <= 1 {
+        (
+            Layout::new_linear_2d(dims.x as usize),
+            Layout::new_morton_2d(dims.x as usize),
+        )
+    } else {
+        (
+            Layout::new_box(dims, Curve::Linear),
+            Layout::new_box(dims, Curve::Morton),
+        )
+    }
+}
+
 #[derive(Resource, Clone)]
-pub struct RandomSearch {
-    pub best_heuristic: usize,
-    pub initial_heuristic: usize,
-    pub linear_heuristic: usize,
-    pub morton_heuristic: usize,
+pub struct RandomSearch<O: Objective = Cost> {
+    pub best_heuristic: O::Score,
+    pub initial_heuristic: O::Score,
+    pub linear_heuristic: O::Score,
+    pub morton_heuristic: O::Score,
+    /// Line crossings of the current layout, refreshed with every progress message.
+    pub line_crossings: usize,
+    /// Lower bound on [`line_crossings`](RandomSearch::line_crossings), see [`bound`].
+    pub lower_bound: usize,
 
     pub per_frame: usize,
     pub iteration: usize,
     pub running: bool,
     pub load: bool,
     pub save_every: usize,
+    pub moves: MoveWeights,
+    /// What `best_heuristic` and the other scores measure.
+    pub cost: O,
+    /// Symmetries the layout keeps, moves are applied to whole orbits under them.
+    pub symmetry: SymmetryGroup,
+    /// Curve order of the layout, kept in sync by the moves instead of rebuilt every iteration.
+    pub order: Vec<U8Vec3>,
 
     pub run_name: &'static str,
 }
 
-impl RandomSearch {
+impl<O: Objective> RandomSearch<O> {
     pub fn current_info(&self) -> String {
         format!(
-            "iter: {}, best: {}, initial: {}",
-            self.iteration, self.best_heuristic, self.initial_heuristic
+            "iter: {}, best: {}, initial: {}, line crossings: {} (lower bound: {}, line crossing gap: {})",
+            self.iteration,
+            self.best_heuristic,
+            self.initial_heuristic,
+            self.line_crossings,
+            self.lower_bound,
+            self.gap_text(),
         )
     }
+
+    /// How far the line crossings are above their lower bound, in percent of the bound. `None` if
+    /// the bound is 0, e.g. when a single line covers the volume. Only the distance to the optimum
+    /// when the search minimizes the line crossings, for other costs just a reference.
+    pub fn gap(&self) -> Option<f32> {
+        (self.lower_bound > 0)
+            .then(|| ((self.line_crossings as f32 / self.lower_bound as f32) - 1.0) * 100.0)
+    }
+
+    fn gap_text(&self) -> String {
+        self.gap().map_or("n/a".to_string(), |gap| format!("{:.2}%", gap))
+    }
 }
 
-pub fn random_search(
+pub fn random_search<O: Objective>(
     mut layout: ResMut<Layout>,
-    mut search: ResMut<RandomSearch>,
+    mut search: ResMut<RandomSearch<O>>,
     input: Res<ButtonInput<KeyCode>>,
 ) {
     if input.just_pressed(KeyCode::KeyR) {
@@ -183,61 +744,62 @@ pub fn random_search(
     // const MAX_SWAPS: usize = 216;
     const PROGRESS: usize = 10_000;
     let mut rng = rand::rng();
+    let dims = layout.dims();
+    let search = search.as_mut();
 
     for _ in 0..search.per_frame {
         // try some random swaps
         if search.iteration % PROGRESS == 0 {
+            search.line_crossings = layout.line_crossings();
             println!(
-                "iteration: {:?}: current best: {:?} ({:?}% initial, {:03?}% linear, {:03?}% morton)",
+                "iteration: {:?}: current best: {:?} ({:?}% initial, {:03?}% linear, {:03?}% morton, {} line crossing gap)",
                 search.iteration,
                 search.best_heuristic,
-                ((search.best_heuristic as f32 / search.initial_heuristic as f32) - 1.0)
+                ((search.best_heuristic.as_f64() / search.initial_heuristic.as_f64()) - 1.0)
                     * -100.0,
-                ((search.best_heuristic as f32 / search.linear_heuristic as f32) - 1.0)
+                ((search.best_heuristic.as_f64() / search.linear_heuristic.as_f64()) - 1.0)
                     * -100.0,
-                ((search.best_heuristic as f32 / search.morton_heuristic as f32) - 1.0)
+                ((search.best_heuristic.as_f64() / search.morton_heuristic.as_f64()) - 1.0)
                     * -100.0,
+                search.gap_text(),
             );
         }
 
-        const MIN: i32 = 0;
-        const MAX: i32 = WIDTH as i32;
-        // const MIN: i32 = 3;
-        // const MAX: i32 = 6;
-
-        let mut swaps = Vec::new();
+        let mut moves = Vec::new();
         for _ in 0..rng.random_range(1..MAX_SWAPS) {
-            let swap_a = IVec3::new(
-                rng.random_range(MIN..MAX),
-                rng.random_range(MIN..MAX),
-                rng.random_range(MIN..MAX),
-            );
-            let swap_b = loop {
-                let b = IVec3::new(
-                    rng.random_range(MIN..MAX),
-                    rng.random_range(MIN..MAX),
-                    rng.random_range(MIN..MAX),
-                );
-                if b != swap_a {
-                    break b;
+            if search.symmetry.is_trivial() {
+                let Some(mv) = search.moves.sample(&search.order, dims, &mut rng) else {
+                    warn!("nothing to move in a layout of {}, stopping the search", dims);
+                    search.running = false;
+                    return;
+                };
+                mv.apply_with_order(&mut layout, &mut search.order);
+                moves.push(mv);
+            } else {
+                let Some(orbit) =
+                    search
+                        .moves
+                        .sample_orbit(&search.order, dims, &search.symmetry, &mut rng)
+                else {
+                    warn!("no swap keeps the symmetry of the layout, stopping the search");
+                    search.running = false;
+                    return;
+                };
+                for mv in orbit {
+                    mv.apply_with_order(&mut layout, &mut search.order);
+                    moves.push(mv);
                 }
-            };
-
-            swaps.push((swap_a, swap_b));
+            }
         }
 
-        // info!("swaps: {:?}", swaps);
-
-        for (swap_a, swap_b) in swaps.iter() {
-            layout.swap(*swap_a, *swap_b);
-        }
+        // info!("moves: {:?}", moves);
 
-        let new_heuristic = layout.heuristic();
+        let new_heuristic = search.cost.evaluate(&layout);
         if new_heuristic <= search.best_heuristic {
             search.best_heuristic = new_heuristic;
         } else {
-            for (swap_a, swap_b) in swaps.iter().rev() {
-                layout.swap(*swap_a, *swap_b);
+            for mv in moves.iter().rev() {
+                mv.inverse().apply_with_order(&mut layout, &mut search.order);
             }
         }
 
@@ -263,17 +825,28 @@ pub fn display_current_layout(
         commands.entity(entity).despawn();
     }
 
-    let mut linearized = vec![Vec3::ZERO; 16 * 16 * 16];
-    for (point, index) in layout.0.iter() {
-        linearized[*index] = point.as_vec3();
-    }
+    let dims = layout.dims().as_vec3();
+    let flat = dims.z <= 1.0;
+    // 2D layouts lie flat on the ground, colored along the curve since they have no depth
+    let linearized: Vec<Vec3> = layout
+        .order()
+        .iter()
+        .map(|point| match flat {
+            true => Vec3::new(point.x as f32, 0.0, point.y as f32),
+            false => point.as_vec3(),
+        })
+        .collect();
 
     let mut gizmos = GizmoAsset::new();
 
-    for window in linearized.windows(2) {
+    for (index, window) in linearized.windows(2).enumerate() {
         let a = window[0];
         let b = window[1];
-        gizmos.line(a, b, Color::srgb(a.x / 16.0, a.y / 16.0, a.z / 16.0));
+        let color = match flat {
+            true => Color::hsl(index as f32 / linearized.len() as f32 * 300.0, 1.0, 0.5),
+            false => Color::srgb(a.x / dims.x, a.y / dims.y, a.z / dims.z),
+        };
+        gizmos.line(a, b, color);
     }
 
     commands.spawn((
@@ -290,10 +863,10 @@ pub fn display_current_layout(
     ));
 }
 
-pub fn write_layout_to_file(
+pub fn write_layout_to_file<O: Objective>(
     layout: Res<Layout>,
     input: Res<ButtonInput<KeyCode>>,
-    search: Res<RandomSearch>,
+    search: Res<RandomSearch<O>>,
 ) {
     // return;
     if !(search.iteration != 0 && search.iteration % search.save_every == 0)
@@ -308,8 +881,13 @@ pub fn write_layout_to_file(
 
     let local_now: chrono::DateTime<chrono::Local> = chrono::Local::now();
     let now = local_now.format("%Y-%m-%d-%H:%M:%S").to_string();
-    let backup_name = format!("./layouts/backup/layout-{}-{}^3-{}.yml", search.run_name, WIDTH, now);
-    let name = format!("./layouts/layout-{}-{}^3.yml", search.run_name, WIDTH);
+    let backup_name = format!(
+        "./layouts/backup/layout-{}-{}-{}.yml",
+        search.run_name,
+        layout.size_name(),
+        now
+    );
+    let name = format!("./layouts/layout-{}-{}.yml", search.run_name, layout.size_name());
     println!("backup_name: {:?}", backup_name);
     let mut current_layout = std::fs::File::create(name).unwrap();
     let mut backup_layout = std::fs::File::create(backup_name).unwrap();
@@ -317,9 +895,9 @@ pub fn write_layout_to_file(
     backup_layout.write_all(layout_buffer.as_bytes()).unwrap();
 }
 
-pub fn load_layout_from_file(
+pub fn load_layout_from_file<O: Objective>(
     mut layout: ResMut<Layout>,
-    mut search: ResMut<RandomSearch>,
+    mut search: ResMut<RandomSearch<O>>,
     input: Res<ButtonInput<KeyCode>>,
 ) {
     // return;
@@ -333,22 +911,56 @@ pub fn load_layout_from_file(
     search.load = false;
 
     info!("LOADING LAYOUT");
-    let name = format!("./layouts/layout-{}-{}^3.yml", search.run_name, WIDTH);
+    let name = format!("./layouts/layout-{}-{}.yml", search.run_name, layout.size_name());
     println!("name: {:?}", name);
     let Ok(layout_str) = std::fs::read_to_string(name.clone()) else {
         warn!("No {:?} saved", name);
         return;
     };
     let deser_layout: Layout = serde_yml::from_str(&layout_str).unwrap();
+    if let Err(err) = search.symmetry.check(&deser_layout) {
+        warn!("{:?} is not symmetric: {}", name, err);
+        return;
+    }
     *layout = deser_layout;
-    search.best_heuristic = layout.heuristic();
-    search.initial_heuristic = layout.heuristic();
+    search.order = layout.order();
+    search.best_heuristic = search.cost.evaluate(&layout);
+    search.initial_heuristic = search.best_heuristic;
+    search.line_crossings = layout.line_crossings();
     search.iteration = 0;
     info!("Resetting search: {:?}", search.current_info());
 }
 
+/// Runs a headless command like `cargo run -- exact 3 8` instead of the search app, if one is given.
+fn run_command() -> Option<AppExit> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("boundaries") => cost::run_boundaries(&args[1..]),
+        Some("bricks") => bricks::run(&args[1..]),
+        Some("chunks") => chunks::run(&args[1..]),
+        Some("compose") => compose::run(&args[1..]),
+        Some("equivalent") => equivalent::run(&args[1..]),
+        Some("exact") => exact::run(&args[1..]),
+        Some("footprint") => cost::run_footprint(&args[1..]),
+        Some("pareto") => pareto::run(&args[1..]),
+        Some("qap") => qap::run(&args[1..]),
+        Some("sweep") => cache::run_sweep(&args[1..]),
+        Some("trace") => trace::run(&args[1..]),
+        Some("morton-bench") => morton::run_bench(&args[1..]),
+        Some(command) => {
+            println!("unknown command: {:?}", command);
+            return Some(AppExit::error());
+        }
+        None => return None,
+    }
+
+    Some(AppExit::Success)
+}
+
 fn main() -> AppExit {
-    compare_bases();
+    if let Some(exit) = run_command() {
+        return exit;
+    }
 
     let mut app = App::new();
     app.add_plugins(DefaultPlugins);
@@ -360,35 +972,108 @@ fn main() -> AppExit {
     let layout = Layout::new_morton();
     // let layout = Layout::new_linear();
     // let layout = Layout::new_random();
+    // let layout = Layout::new_hilbert_2d(64);
+    // let block = Layout::read("./layouts/layout-4^3.yml").unwrap();
+    // let layout = Layout::compose_levels(&block, 2);
+    // let layout = Layout::new_box(UVec3::splat(WIDTH as u32 + 2), Curve::Morton);
     println!("initial heuristic: {:?}", layout.heuristic());
 
-    app.insert_resource(RandomSearch {
-        best_heuristic: layout.heuristic(),
-        initial_heuristic: layout.heuristic(),
-        linear_heuristic: Layout::new_linear().heuristic(),
-        morton_heuristic: Layout::new_morton().heuristic(),
-        per_frame: 10,
-        iteration: 0,
-        running: true,
-        load: true,
-        save_every: 1_000_000,
+    let cost = Cost::Heuristic;
+    // let cost = Cost::Trace {
+    //     trace: std::sync::Arc::new(trace::Trace::read("./traces/meshing.trace").unwrap()),
+    //     model: CacheModel {
+    //         levels: vec![cache::CacheConfig { size: 2048, ..default() }],
+    //         ..default()
+    //     },
+    // };
+    // let cost = Cost::Boundaries(cost::Boundaries::default());
+    // let cost = Cost::Chunks {
+    //     stencil: Stencil::moore(),
+    //     chunks: chunks::Chunks::default(),
+    // };
+    // let cost = Cost::Apron {
+    //     stencil: Stencil::moore(),
+    //     apron: 1,
+    // };
+    // let cost = Cost::Footprint {
+    //     stencil: Stencil::moore(),
+    //     line_voxels: LINE_VOXELS,
+    //     aggregate: cost::Aggregate::Percentile(99),
+    // };
+    // let cost = Cost::Sweep {
+    //     stencil: Stencil::moore(),
+    //     iteration: Iteration::Memory,
+    //     model: CacheModel {
+    //         levels: vec![cache::CacheConfig { size: 2048, ..default() }],
+    //         prefetcher: cache::PrefetcherConfig { next_line: true, streams: 8, ..default() },
+    //     },
+    // };
 
-        run_name: "cache-morton",
-    });
+    let symmetry = SymmetryGroup::trivial();
+    // let symmetry = SymmetryGroup::named("prism-z").unwrap();
+    if let Err(err) = symmetry.check(&layout) {
+        println!("initial layout is not symmetric: {}", err);
+        return AppExit::error();
+    }
+
+    // enumerates every densest arrangement of a line, slow enough to only do it once
+    let lower_bound = bound::line_crossings_lower_bound(layout.dims(), LINE_VOXELS);
+    println!("line crossings lower bound: {}", lower_bound);
+
+    compare_bases(&cost);
+    add_search(&mut app, &layout, cost, symmetry, lower_bound);
+    // let cost = cost::Continuous::new(Stencil::moore());
+    // compare_bases(&cost);
+    // add_search(&mut app, &layout, cost, symmetry, lower_bound);
     app.insert_resource(layout);
     app.insert_resource(AmbientLight {
         brightness: 2500.0,
         ..default()
     });
 
-    app.add_systems(Update, random_search);
     app.add_systems(Update, display_current_layout);
 
-    app.add_systems(Update, (load_layout_from_file, write_layout_to_file));
-
     app.run()
 }
 
+/// Inserts the search for `cost` and its systems, `lower_bound` being the line crossings lower
+/// bound of `layout`'s volume.
+fn add_search<O: Objective>(
+    app: &mut App,
+    layout: &Layout,
+    cost: O,
+    symmetry: SymmetryGroup,
+    lower_bound: usize,
+) {
+    let (linear, morton) = baselines(layout);
+    app.insert_resource(RandomSearch {
+        best_heuristic: cost.evaluate(layout),
+        initial_heuristic: cost.evaluate(layout),
+        linear_heuristic: cost.evaluate(&linear),
+        morton_heuristic: cost.evaluate(&morton),
+        line_crossings: layout.line_crossings(),
+        lower_bound,
+        per_frame: 10,
+        iteration: 0,
+        running: true,
+        load: true,
+        save_every: 1_000_000,
+        moves: MoveWeights::swaps(),
+        // moves: MoveWeights {
+        //     proposals: SwapProposals::mixture(),
+        //     ..default()
+        // },
+        cost,
+        symmetry,
+        order: layout.order(),
+
+        run_name: "cache-morton",
+    });
+
+    app.add_systems(Update, random_search::<O>);
+    app.add_systems(Update, (load_layout_from_file::<O>, write_layout_to_file::<O>));
+}
+
 pub fn spawn_entities(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -410,3 +1095,14 @@ pub fn spawn_entities(
         MeshMaterial3d(materials.add(Color::from(palettes::css::SILVER))),
     ));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "too large")]
+    fn layouts_past_the_coordinate_limit_panic() {
+        Layout::new_linear_2d(300);
+    }
+}
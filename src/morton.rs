@@ -1,20 +1,20 @@
+use bevy::math::{U8Vec3, U64Vec3};
 use bevy::prelude::*;
 
 // Morton Curve (Z-Order) implementation for 3D points in Rust
-// Range: 0..16 (exclusive) for x, y, z coordinates
+// Range: 0..256 for `*_8`, 0..1024 for the default u32 and 0..2097152 for the u64 `*_21`
+// x, y, z coordinates
 
 /// Spreads bits of a number by inserting two zeros between each bit
 /// Used to prepare coordinates for Morton encoding
 #[inline]
 fn spread_bits(mut value: u32) -> u32 {
-    // Ensure value is within 4-bit range (0-15)
-    // value &= 0xF;
-
-    // Spread the 4 bits across 12 bits with 2 zeros between each bit
-    // 0000abcd -> 00a00b00c00d
-    value = (value | (value << 8)) & 0x00F00F; // 0000abcd -> 0000ab0000cd
-    value = (value | (value << 4)) & 0x0C30C3; // 0000ab0000cd -> 00a0b00c0d
-    value = (value | (value << 2)) & 0x249249; // 00a0b00c0d -> 0a0b0c0d
+    // Spread the 10 bits across 30 bits with 2 zeros between each bit
+    value &= 0x3FF;
+    value = (value | (value << 16)) & 0x030000FF;
+    value = (value | (value << 8)) & 0x0300F00F;
+    value = (value | (value << 4)) & 0x030C30C3;
+    value = (value | (value << 2)) & 0x09249249;
 
     value
 }
@@ -23,24 +23,99 @@ fn spread_bits(mut value: u32) -> u32 {
 /// Reverses the spread_bits operation
 #[inline]
 fn compact_bits(mut value: u32) -> u32 {
-    // Compact the spread bits back to 4 bits
+    // Compact the spread bits back to 10 bits
+    value &= 0x09249249;
+    value = (value | (value >> 2)) & 0x030C30C3;
+    value = (value | (value >> 4)) & 0x0300F00F;
+    value = (value | (value >> 8)) & 0x030000FF;
+    value = (value | (value >> 16)) & 0x000003FF;
+
+    value
+}
+
+/// [`spread_bits`] for 8 bit values, spread across 24 bits
+#[inline]
+fn spread_bits_8(value: u8) -> u32 {
+    let mut value = value as u32;
+    // Spread the 8 bits across 24 bits, a nibble at a time
+    value = (value | (value << 8)) & 0x00F00F;
+    value = (value | (value << 4)) & 0x0C30C3;
+    value = (value | (value << 2)) & 0x249249;
+
+    value
+}
+
+/// Reverses the spread_bits_8 operation
+#[inline]
+fn compact_bits_8(mut value: u32) -> u8 {
     value &= 0x249249;
     value = (value | (value >> 2)) & 0x0C30C3;
     value = (value | (value >> 4)) & 0x00F00F;
-    value = (value | (value >> 8)) & 0x00000F;
+    value = (value | (value >> 8)) & 0x0000FF;
+
+    value as u8
+}
+
+/// [`spread_bits`] for 21 bit values, spread across 63 bits
+#[inline]
+fn spread_bits_21(mut value: u64) -> u64 {
+    value &= 0x1FFFFF;
+    value = (value | (value << 32)) & 0x001F00000000FFFF;
+    value = (value | (value << 16)) & 0x001F0000FF0000FF;
+    value = (value | (value << 8)) & 0x100F00F00F00F00F;
+    value = (value | (value << 4)) & 0x10C30C30C30C30C3;
+    value = (value | (value << 2)) & 0x1249249249249249;
+
+    value
+}
+
+/// Reverses the spread_bits_21 operation
+#[inline]
+fn compact_bits_21(mut value: u64) -> u64 {
+    value &= 0x1249249249249249;
+    value = (value | (value >> 2)) & 0x10C30C30C30C30C3;
+    value = (value | (value >> 4)) & 0x100F00F00F00F00F;
+    value = (value | (value >> 8)) & 0x001F0000FF0000FF;
+    value = (value | (value >> 16)) & 0x001F00000000FFFF;
+    value = (value | (value >> 32)) & 0x00000000001FFFFF;
 
     value
 }
 
 /// Converts 3D coordinates to Morton index (linearization)
+///
+/// Uses BMI2 `pdep` when the CPU supports it, [`to_morton_index_portable`] otherwise.
 #[inline]
 pub fn to_morton_index(point: UVec3) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if std::is_x86_feature_detected!("bmi2") {
+        // SAFETY: bmi2 is supported, checked above
+        return unsafe { bmi2::to_morton_index(point) };
+    }
+
+    to_morton_index_portable(point)
+}
+
+#[inline]
+pub fn from_morton_index(index: u32) -> UVec3 {
+    #[cfg(target_arch = "x86_64")]
+    if std::is_x86_feature_detected!("bmi2") {
+        // SAFETY: bmi2 is supported, checked above
+        return unsafe { bmi2::from_morton_index(index) };
+    }
+
+    from_morton_index_portable(index)
+}
+
+/// [`to_morton_index`] using the magic number shifts, works everywhere
+#[inline]
+pub fn to_morton_index_portable(point: UVec3) -> u32 {
     // Interleave bits: z gets the highest bits, then y, then x
     spread_bits(point.x) | (spread_bits(point.y) << 1) | (spread_bits(point.z) << 2)
 }
 
 #[inline]
-pub fn from_morton_index(index: u32) -> UVec3 {
+pub fn from_morton_index_portable(index: u32) -> UVec3 {
     // Extract interleaved bits for each coordinate
     let x = compact_bits(index); // Extract every 3rd bit starting from bit 0
     let y = compact_bits(index >> 1); // Extract every 3rd bit starting from bit 1
@@ -48,3 +123,472 @@ pub fn from_morton_index(index: u32) -> UVec3 {
 
     UVec3 { x, y, z }
 }
+
+#[inline]
+pub fn to_morton_index_8(point: U8Vec3) -> u32 {
+    spread_bits_8(point.x) | (spread_bits_8(point.y) << 1) | (spread_bits_8(point.z) << 2)
+}
+
+#[inline]
+pub fn from_morton_index_8(index: u32) -> U8Vec3 {
+    U8Vec3 {
+        x: compact_bits_8(index),
+        y: compact_bits_8(index >> 1),
+        z: compact_bits_8(index >> 2),
+    }
+}
+
+#[inline]
+pub fn to_morton_index_21(point: U64Vec3) -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    if std::is_x86_feature_detected!("bmi2") {
+        // SAFETY: bmi2 is supported, checked above
+        return unsafe { bmi2::to_morton_index_21(point) };
+    }
+
+    to_morton_index_21_portable(point)
+}
+
+#[inline]
+pub fn from_morton_index_21(index: u64) -> U64Vec3 {
+    #[cfg(target_arch = "x86_64")]
+    if std::is_x86_feature_detected!("bmi2") {
+        // SAFETY: bmi2 is supported, checked above
+        return unsafe { bmi2::from_morton_index_21(index) };
+    }
+
+    from_morton_index_21_portable(index)
+}
+
+#[inline]
+pub fn to_morton_index_21_portable(point: U64Vec3) -> u64 {
+    spread_bits_21(point.x) | (spread_bits_21(point.y) << 1) | (spread_bits_21(point.z) << 2)
+}
+
+#[inline]
+pub fn from_morton_index_21_portable(index: u64) -> U64Vec3 {
+    U64Vec3 {
+        x: compact_bits_21(index),
+        y: compact_bits_21(index >> 1),
+        z: compact_bits_21(index >> 2),
+    }
+}
+
+/// Encodes every point of `points` into `indices`, identical to [`to_morton_index`] on each point
+///
+/// `pdep` only works on one scalar at a time, so slices use the magic number shifts instead: the
+/// loop has no branches or lookups and LLVM vectorizes it across several points per instruction.
+pub fn encode_many(points: &[UVec3], indices: &mut [u32]) {
+    assert_eq!(points.len(), indices.len());
+    for (point, index) in points.iter().zip(indices.iter_mut()) {
+        *index = to_morton_index_portable(*point);
+    }
+}
+
+/// Decodes every index of `indices` into `points`, identical to [`from_morton_index`] on each index
+pub fn decode_many(indices: &[u32], points: &mut [UVec3]) {
+    assert_eq!(points.len(), indices.len());
+    for (index, point) in indices.iter().zip(points.iter_mut()) {
+        *point = from_morton_index_portable(*index);
+    }
+}
+
+/// `pdep`/`pext` deposit/extract each coordinate straight into/from its Morton bits.
+///
+/// Note these are microcoded (slow) on AMD before Zen 3, benchmark with `morton-bench`.
+#[cfg(target_arch = "x86_64")]
+mod bmi2 {
+    use super::{MORTON_X, MORTON_Y, MORTON_Z};
+    use bevy::math::U64Vec3;
+    use bevy::prelude::*;
+    use std::arch::x86_64::{_pdep_u32, _pdep_u64, _pext_u32, _pext_u64};
+
+    const MORTON_X_21: u64 = 0x1249249249249249;
+
+    #[target_feature(enable = "bmi2")]
+    pub fn to_morton_index(point: UVec3) -> u32 {
+        _pdep_u32(point.x, MORTON_X) | _pdep_u32(point.y, MORTON_Y) | _pdep_u32(point.z, MORTON_Z)
+    }
+
+    #[target_feature(enable = "bmi2")]
+    pub fn from_morton_index(index: u32) -> UVec3 {
+        UVec3 {
+            x: _pext_u32(index, MORTON_X),
+            y: _pext_u32(index, MORTON_Y),
+            z: _pext_u32(index, MORTON_Z),
+        }
+    }
+
+    #[target_feature(enable = "bmi2")]
+    pub fn to_morton_index_21(point: U64Vec3) -> u64 {
+        _pdep_u64(point.x, MORTON_X_21)
+            | _pdep_u64(point.y, MORTON_X_21 << 1)
+            | _pdep_u64(point.z, MORTON_X_21 << 2)
+    }
+
+    #[target_feature(enable = "bmi2")]
+    pub fn from_morton_index_21(index: u64) -> U64Vec3 {
+        U64Vec3 {
+            x: _pext_u64(index, MORTON_X_21),
+            y: _pext_u64(index, MORTON_X_21 << 1),
+            z: _pext_u64(index, MORTON_X_21 << 2),
+        }
+    }
+}
+
+/// `morton-bench [count]`: times the BMI2 and portable encode/decode on `count` coordinates
+pub fn run_bench(args: &[String]) {
+    use std::hint::black_box;
+    use std::time::Instant;
+
+    let count: u32 = args
+        .first()
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(10_000_000);
+    let points: Vec<UVec3> = (0..count)
+        .map(|i| from_morton_index_portable(i.wrapping_mul(2654435761) & 0x3FFFFFFF))
+        .collect();
+
+    fn time(name: &str, points: &[UVec3], f: impl Fn(UVec3) -> UVec3) {
+        let start = Instant::now();
+        let mut check = 0;
+        for &point in points {
+            check ^= black_box(f(black_box(point))).x;
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{}: {:?} ({:.2} ns/coordinate, check {})",
+            name,
+            elapsed,
+            elapsed.as_nanos() as f64 / points.len() as f64,
+            check,
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    println!("bmi2 detected: {}", std::is_x86_feature_detected!("bmi2"));
+
+    time("portable encode", &points, |point| UVec3::splat(to_morton_index_portable(point)));
+    time("encode", &points, |point| UVec3::splat(to_morton_index(point)));
+    time("portable decode", &points, |point| from_morton_index_portable(point.x));
+    time("decode", &points, |point| from_morton_index(point.x));
+
+    let mut indices = vec![0; points.len()];
+    let start = Instant::now();
+    encode_many(black_box(&points), &mut indices);
+    println!("encode_many: {:?}", start.elapsed());
+
+    let mut decoded = vec![UVec3::ZERO; points.len()];
+    let start = Instant::now();
+    decode_many(black_box(&indices), &mut decoded);
+    println!("decode_many: {:?}", start.elapsed());
+    assert_eq!(decoded, points);
+}
+
+/// [`spread_bits`] for 2D, spreads 16 bits across 32 bits with 1 zero between each bit
+#[inline]
+fn spread_bits_2d(mut value: u32) -> u32 {
+    value &= 0x0000FFFF;
+    value = (value | (value << 8)) & 0x00FF00FF;
+    value = (value | (value << 4)) & 0x0F0F0F0F;
+    value = (value | (value << 2)) & 0x33333333;
+    value = (value | (value << 1)) & 0x55555555;
+
+    value
+}
+
+/// Reverses the spread_bits_2d operation
+#[inline]
+fn compact_bits_2d(mut value: u32) -> u32 {
+    value &= 0x55555555;
+    value = (value | (value >> 1)) & 0x33333333;
+    value = (value | (value >> 2)) & 0x0F0F0F0F;
+    value = (value | (value >> 4)) & 0x00FF00FF;
+    value = (value | (value >> 8)) & 0x0000FFFF;
+
+    value
+}
+
+/// Converts 2D coordinates (0..65536) to Morton index, y gets the higher bit
+#[inline]
+pub fn to_morton_index_2d(point: UVec2) -> u32 {
+    spread_bits_2d(point.x) | (spread_bits_2d(point.y) << 1)
+}
+
+#[inline]
+pub fn from_morton_index_2d(index: u32) -> UVec2 {
+    UVec2 {
+        x: compact_bits_2d(index),
+        y: compact_bits_2d(index >> 1),
+    }
+}
+
+/// Bits of the default u32 Morton index that belong to x, y and z
+pub const MORTON_X: u32 = 0x09249249;
+pub const MORTON_Y: u32 = MORTON_X << 1;
+pub const MORTON_Z: u32 = MORTON_X << 2;
+pub const MORTON_AXES: [u32; 3] = [MORTON_X, MORTON_Y, MORTON_Z];
+
+/// Adds the `mask` component of two Morton indices without decoding them
+///
+/// Filling the other axes' bits of `a` with ones lets the carry skip over them; a carry out of
+/// the top means the coordinate left the 0..1024 range.
+#[inline]
+fn add_axis(a: u32, b: u32, mask: u32) -> Option<u32> {
+    let (sum, overflow) = (a | !mask).overflowing_add(b & mask);
+    (!overflow).then_some(sum & mask)
+}
+
+/// Subtracts the `mask` component of two Morton indices, `None` if it would go negative
+#[inline]
+fn sub_axis(a: u32, b: u32, mask: u32) -> Option<u32> {
+    let (difference, underflow) = (a & mask).overflowing_sub(b & mask);
+    (!underflow).then_some(difference & mask)
+}
+
+/// Adds two Morton indices component-wise, `None` if any coordinate leaves 0..1024
+#[inline]
+pub fn morton_add(a: u32, b: u32) -> Option<u32> {
+    Some(add_axis(a, b, MORTON_X)? | add_axis(a, b, MORTON_Y)? | add_axis(a, b, MORTON_Z)?)
+}
+
+/// Subtracts two Morton indices component-wise, `None` if any coordinate goes negative
+#[inline]
+pub fn morton_sub(a: u32, b: u32) -> Option<u32> {
+    Some(sub_axis(a, b, MORTON_X)? | sub_axis(a, b, MORTON_Y)? | sub_axis(a, b, MORTON_Z)?)
+}
+
+/// Steps a Morton index by +1 along `axis` (0 = x, 1 = y, 2 = z)
+#[inline]
+pub fn morton_increment(index: u32, axis: usize) -> Option<u32> {
+    let mask = MORTON_AXES[axis];
+    Some(add_axis(index, 1 << axis, mask)? | (index & !mask))
+}
+
+/// Steps a Morton index by -1 along `axis` (0 = x, 1 = y, 2 = z)
+#[inline]
+pub fn morton_decrement(index: u32, axis: usize) -> Option<u32> {
+    let mask = MORTON_AXES[axis];
+    Some(sub_axis(index, 1 << axis, mask)? | (index & !mask))
+}
+
+/// Offsets a Morton index by `offset`, e.g. one of the 26 neighbor offsets, `None` if any
+/// coordinate leaves 0..1024
+#[inline]
+pub fn morton_offset(index: u32, offset: IVec3) -> Option<u32> {
+    // larger components don't fit in the 10 bits an axis encodes, and would wrap
+    if offset.cmple(IVec3::splat(-(1 << 10))).any() || offset.cmpge(IVec3::splat(1 << 10)).any() {
+        return None;
+    }
+    let positive = to_morton_index(offset.max(IVec3::ZERO).as_uvec3());
+    let negative = to_morton_index((-offset).max(IVec3::ZERO).as_uvec3());
+    morton_sub(morton_add(index, positive)?, negative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    /// Bit by bit reference for the magic number versions.
+    fn spread_reference(value: u64, bits: u32) -> u64 {
+        (0..bits).fold(0, |spread, bit| spread | (((value >> bit) & 1) << (3 * bit)))
+    }
+
+    #[test]
+    fn spread_bits_matches_reference() {
+        for value in 0..1 << 8 {
+            assert_eq!(spread_bits_8(value as u8) as u64, spread_reference(value, 8));
+        }
+        for value in 0..1 << 10 {
+            assert_eq!(spread_bits(value as u32) as u64, spread_reference(value, 10));
+        }
+        for value in 0..1 << 21 {
+            assert_eq!(spread_bits_21(value), spread_reference(value, 21));
+        }
+    }
+
+    #[test]
+    fn morton_index_2d_matches_reference() {
+        for value in 0..1 << 16 {
+            let reference = (0..16).fold(0, |spread, bit| {
+                spread | (((value >> bit) & 1) << (2 * bit))
+            });
+            assert_eq!(spread_bits_2d(value), reference);
+            assert_eq!(compact_bits_2d(reference), value);
+        }
+        for index in 0..1 << 24 {
+            assert_eq!(to_morton_index_2d(from_morton_index_2d(index)), index);
+        }
+    }
+
+    #[test]
+    fn compact_bits_round_trip() {
+        for value in 0..=u8::MAX {
+            assert_eq!(compact_bits_8(spread_bits_8(value)), value);
+        }
+        for value in 0..1 << 10 {
+            assert_eq!(compact_bits(spread_bits(value)), value);
+        }
+        for value in 0..1 << 21 {
+            assert_eq!(compact_bits_21(spread_bits_21(value)), value);
+        }
+    }
+
+    #[test]
+    fn morton_index_round_trip_8() {
+        for x in 0..=u8::MAX {
+            for y in 0..=u8::MAX {
+                for z in 0..=u8::MAX {
+                    let point = U8Vec3::new(x, y, z);
+                    assert_eq!(from_morton_index_8(to_morton_index_8(point)), point);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn morton_index_round_trip() {
+        // each axis exhaustively, with the other two at their extremes
+        for value in 0..1 << 10 {
+            for other in [0, (1 << 10) - 1] {
+                for point in [
+                    UVec3::new(value, other, other),
+                    UVec3::new(other, value, other),
+                    UVec3::new(other, other, value),
+                ] {
+                    assert_eq!(from_morton_index(to_morton_index(point)), point);
+                }
+            }
+        }
+
+        // and a sample of the full 10 bit cube
+        let mut rng = rand::rng();
+        for _ in 0..1 << 16 {
+            let index = rng.random_range(0..1 << 30);
+            assert_eq!(to_morton_index(from_morton_index(index)), index);
+        }
+    }
+
+    #[test]
+    fn morton_index_round_trip_21() {
+        // each axis exhaustively, with the other two at their extremes
+        for value in 0..1 << 21 {
+            for other in [0, (1 << 21) - 1] {
+                for point in [
+                    U64Vec3::new(value, other, other),
+                    U64Vec3::new(other, value, other),
+                    U64Vec3::new(other, other, value),
+                ] {
+                    assert_eq!(from_morton_index_21(to_morton_index_21(point)), point);
+                }
+            }
+        }
+    }
+
+    /// Decodes, offsets and re-encodes, the slow path the Morton arithmetic has to match.
+    fn offset_reference(index: u32, offset: IVec3) -> Option<u32> {
+        let point = from_morton_index(index).as_ivec3() + offset;
+        (point.cmpge(IVec3::ZERO).all() && point.cmplt(IVec3::splat(1 << 10)).all())
+            .then(|| to_morton_index(point.as_uvec3()))
+    }
+
+    /// A 34^3 cube of indices touching both ends of the 10 bit range.
+    fn edge_indices() -> impl Iterator<Item = u32> {
+        let coordinates = (0..17).chain((1 << 10) - 17..1 << 10);
+        coordinates.clone().flat_map(move |x| {
+            let coordinates = coordinates.clone();
+            coordinates.clone().flat_map(move |y| {
+                coordinates
+                    .clone()
+                    .map(move |z| to_morton_index(UVec3::new(x, y, z)))
+            })
+        })
+    }
+
+    #[test]
+    fn morton_step_matches_reference() {
+        for index in edge_indices() {
+            for axis in 0..3 {
+                let step = IVec3::AXES[axis];
+                assert_eq!(morton_increment(index, axis), offset_reference(index, step));
+                assert_eq!(morton_decrement(index, axis), offset_reference(index, -step));
+            }
+        }
+    }
+
+    #[test]
+    fn morton_offset_matches_reference() {
+        // neighbors, the whole range and past it
+        let components = [-5000, -1024, -1023, -1, 0, 1, 17, 1023, 1024, 5000];
+        for index in edge_indices() {
+            for &x in components.iter() {
+                for &y in components.iter() {
+                    for &z in components.iter() {
+                        let offset = IVec3::new(x, y, z);
+                        assert_eq!(morton_offset(index, offset), offset_reference(index, offset));
+                    }
+                }
+            }
+        }
+        assert_eq!(morton_offset(0, IVec3::new(1024, 0, 0)), None);
+        assert_eq!(morton_offset(0, IVec3::new(i32::MIN, 0, i32::MAX)), None);
+    }
+
+    #[test]
+    fn morton_add_sub_match_reference() {
+        let offsets = [0, 1, 2, 5, 16, 511, 512, 1000, 1023];
+        for index in edge_indices() {
+            for &x in offsets.iter() {
+                for &y in offsets.iter() {
+                    let offset = IVec3::new(x, y, 1023 - x);
+                    let encoded = to_morton_index(offset.as_uvec3());
+                    assert_eq!(morton_add(index, encoded), offset_reference(index, offset));
+                    assert_eq!(morton_sub(index, encoded), offset_reference(index, -offset));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dispatch_matches_portable() {
+        for index in 0..1 << 24 {
+            let point = from_morton_index_portable(index);
+            assert_eq!(from_morton_index(index), point);
+            assert_eq!(to_morton_index(point), to_morton_index_portable(point));
+
+            let index = (index as u64) << 39 | index as u64;
+            let point = from_morton_index_21_portable(index);
+            assert_eq!(from_morton_index_21(index), point);
+            assert_eq!(to_morton_index_21(point), to_morton_index_21_portable(point));
+        }
+    }
+
+    #[test]
+    fn batch_matches_scalar() {
+        let indices: Vec<u32> = (0..1 << 20)
+            .map(|i: u32| i.wrapping_mul(2654435761) >> 2)
+            .collect();
+        let mut points = vec![UVec3::ZERO; indices.len()];
+        decode_many(&indices, &mut points);
+        for (index, point) in indices.iter().zip(points.iter()) {
+            assert_eq!(from_morton_index(*index), *point);
+        }
+
+        let mut encoded = vec![0; points.len()];
+        encode_many(&points, &mut encoded);
+        for (point, index) in points.iter().zip(encoded.iter()) {
+            assert_eq!(to_morton_index(*point), *index);
+        }
+        assert_eq!(encoded, indices);
+    }
+
+    #[test]
+    fn widths_agree() {
+        for index in 0..1 << 24 {
+            let point = from_morton_index(index);
+            assert_eq!(to_morton_index_8(point.as_u8vec3()), index);
+            assert_eq!(to_morton_index_21(point.as_u64vec3()), index as u64);
+        }
+    }
+}
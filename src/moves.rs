@@ -0,0 +1,577 @@
+use bevy::{math::U8Vec3, prelude::*};
+use rand::Rng;
+use rand::distr::{Distribution, weighted::WeightedIndex};
+
+use crate::{LINE_VOXELS, Layout, WIDTH, symmetry::SymmetryGroup};
+
+// Moves on a layout.
+//
+// Besides swapping the indices of two cells, a layout can be treated as an ordering of cells
+// (the inverse permutation, index -> cell) and modified with TSP-style moves on that sequence.
+// These keep most of the ordering intact, so they preserve locality much better than swaps.
+
+/// A single modification of a [`Layout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Move {
+    /// Swap the indices of two cells.
+    Swap(IVec3, IVec3),
+    /// Reverse the run of indices `start..=end` (2-opt).
+    Reverse { start: usize, end: usize },
+    /// Move the run `start..start + len` so that it begins at index `to` (Or-opt).
+    Relocate { start: usize, len: usize, to: usize },
+    /// Rotate the run `start..=end` left by `shift`.
+    Rotate { start: usize, end: usize, shift: usize },
+}
+
+impl Move {
+    /// Maps an index before the move to the index after the move.
+    ///
+    /// Swaps act on cells rather than indices, so they leave every index as is.
+    pub fn remap(&self, index: usize) -> usize {
+        match *self {
+            Move::Swap(..) => index,
+            Move::Reverse { start, end } => {
+                if (start..=end).contains(&index) {
+                    start + end - index
+                } else {
+                    index
+                }
+            }
+            Move::Relocate { .. } => self.as_rotate().remap(index),
+            Move::Rotate { start, end, shift } => {
+                if (start..=end).contains(&index) {
+                    let len = end - start + 1;
+                    start + (index - start + len - shift % len) % len
+                } else {
+                    index
+                }
+            }
+        }
+    }
+
+    /// Expresses an Or-opt relocation as the rotation of the run it moves across.
+    fn as_rotate(&self) -> Move {
+        match *self {
+            Move::Relocate { start, len, to } if to < start => Move::Rotate {
+                start: to,
+                end: start + len - 1,
+                shift: start - to,
+            },
+            Move::Relocate { start, len, to } if to > start => Move::Rotate {
+                start,
+                end: to + len - 1,
+                shift: len,
+            },
+            Move::Relocate { start, .. } => Move::Rotate {
+                start,
+                end: start,
+                shift: 0,
+            },
+            other => other,
+        }
+    }
+
+    /// The move that undoes this one.
+    pub fn inverse(&self) -> Move {
+        match *self {
+            Move::Swap(..) | Move::Reverse { .. } => *self,
+            Move::Relocate { start, len, to } => Move::Relocate {
+                start: to,
+                len,
+                to: start,
+            },
+            Move::Rotate { start, end, shift } => {
+                let len = end - start + 1;
+                Move::Rotate {
+                    start,
+                    end,
+                    shift: (len - shift % len) % len,
+                }
+            }
+        }
+    }
+
+    pub fn apply(&self, layout: &mut Layout) {
+        match *self {
+            Move::Swap(a, b) => layout.swap(a, b),
+            _ => layout.remap(|index| self.remap(index)),
+        }
+    }
+
+    /// Applies the move to `layout` and keeps its curve `order` (see [`Layout::order`]) in sync.
+    ///
+    /// Unlike [`Move::apply`], only the cells whose index changes are touched.
+    pub fn apply_with_order(&self, layout: &mut Layout, order: &mut [U8Vec3]) {
+        match self.as_rotate() {
+            Move::Swap(a, b) => {
+                layout.swap(a, b);
+                order[layout.position(a)] = a.as_u8vec3();
+                order[layout.position(b)] = b.as_u8vec3();
+            }
+            Move::Reverse { start, end } => {
+                order[start..=end].reverse();
+                layout.reindex(order, start..=end);
+            }
+            Move::Rotate { start, end, shift } => {
+                let len = end - start + 1;
+                order[start..=end].rotate_left(shift % len);
+                layout.reindex(order, start..=end);
+            }
+            Move::Relocate { .. } => unreachable!(),
+        }
+    }
+}
+
+/// Relative weights of each kind of [`Move`] when proposing random moves.
+#[derive(Debug, Clone)]
+pub struct MoveWeights {
+    pub swap: f32,
+    pub reverse: f32,
+    pub relocate: f32,
+    pub rotate: f32,
+
+    /// Longest run of indices a reversal, relocation or rotation touches.
+    pub max_segment: usize,
+    /// How the two cells of a swap are picked.
+    pub proposals: SwapProposals,
+}
+
+impl Default for MoveWeights {
+    fn default() -> Self {
+        Self {
+            swap: 1.0,
+            reverse: 1.0,
+            relocate: 1.0,
+            rotate: 1.0,
+            max_segment: 64,
+            proposals: SwapProposals::default(),
+        }
+    }
+}
+
+impl MoveWeights {
+    /// Only uniform swaps, the original search behaviour.
+    pub fn swaps() -> Self {
+        Self {
+            swap: 1.0,
+            reverse: 0.0,
+            relocate: 0.0,
+            rotate: 0.0,
+            ..default()
+        }
+    }
+
+    /// A proposed swap together with its images under `group`, see
+    /// [`SymmetryGroup::orbit_swaps`]. Segment moves don't commute with the symmetries, so only
+    /// the swap proposals are used.
+    ///
+    /// `None` if none of [`ORBIT_ATTEMPTS`](Self::ORBIT_ATTEMPTS) proposals gives a swap the
+    /// group allows, e.g. the full group on a box too small to have any.
+    pub fn sample_orbit(
+        &self,
+        order: &[U8Vec3],
+        dims: UVec3,
+        group: &SymmetryGroup,
+        rng: &mut impl Rng,
+    ) -> Option<Vec<Move>> {
+        if order.len() < 2 {
+            return None;
+        }
+
+        (0..Self::ORBIT_ATTEMPTS).find_map(|_| {
+            let Move::Swap(a, b) = self.proposals.sample(order, dims, rng) else {
+                return None;
+            };
+            let swaps = group.orbit_swaps(a, b, dims.as_ivec3())?;
+            Some(swaps.into_iter().map(|(a, b)| Move::Swap(a, b)).collect())
+        })
+    }
+
+    /// Proposals [`MoveWeights::sample_orbit`] tries before giving up.
+    pub const ORBIT_ATTEMPTS: usize = 1000;
+
+    /// Proposes a random move, `order` being the current curve order of a layout of `dims`.
+    /// `None` if the layout has fewer than 2 cells, nothing can move then.
+    pub fn sample(&self, order: &[U8Vec3], dims: UVec3, rng: &mut impl Rng) -> Option<Move> {
+        let weights = WeightedIndex::new([self.swap, self.reverse, self.relocate, self.rotate])
+            .expect("at least one move weight must be positive");
+
+        let cells = order.len();
+        if cells < 2 {
+            return None;
+        }
+        let max_segment = self.max_segment.clamp(2, cells);
+        let segment = rng.random_range(2..=max_segment);
+        let start = rng.random_range(0..=cells - segment);
+
+        Some(match weights.sample(rng) {
+            0 => self.proposals.sample(order, dims, rng),
+            1 => Move::Reverse {
+                start,
+                end: start + segment - 1,
+            },
+            2 => {
+                let len = rng.random_range(1..segment);
+                Move::Relocate {
+                    start,
+                    len,
+                    to: rng.random_range(0..=cells - len),
+                }
+            }
+            _ => Move::Rotate {
+                start,
+                end: start + segment - 1,
+                shift: rng.random_range(1..segment),
+            },
+        })
+    }
+}
+
+/// Relative weights of the distributions the two cells of a swap are drawn from.
+#[derive(Debug, Clone)]
+pub struct SwapProposals {
+    /// Both cells anywhere in the volume.
+    pub uniform: f32,
+    /// The second cell within [`radius`](SwapProposals::radius) of the first along every axis.
+    pub near: f32,
+    /// Cells whose indices are at most [`index_window`](SwapProposals::index_window) apart.
+    pub close_index: f32,
+    /// The second cell in the same or an adjacent cache line as the first.
+    pub cache_line: f32,
+    /// Both cells inside `box_min..box_max`, clamped to the layout.
+    pub sub_box: f32,
+
+    pub radius: i32,
+    pub index_window: usize,
+    pub box_min: IVec3,
+    pub box_max: IVec3,
+}
+
+impl Default for SwapProposals {
+    /// Only uniform swaps, the original search behaviour.
+    fn default() -> Self {
+        Self {
+            uniform: 1.0,
+            near: 0.0,
+            close_index: 0.0,
+            cache_line: 0.0,
+            sub_box: 0.0,
+
+            radius: 2,
+            index_window: 64,
+            box_min: IVec3::ZERO,
+            box_max: IVec3::splat(WIDTH as i32),
+        }
+    }
+}
+
+impl SwapProposals {
+    /// Uniform, near, close index and cache line swaps in equal parts.
+    pub fn mixture() -> Self {
+        Self {
+            near: 1.0,
+            close_index: 1.0,
+            cache_line: 1.0,
+            ..default()
+        }
+    }
+
+    pub fn sample(&self, order: &[U8Vec3], dims: UVec3, rng: &mut impl Rng) -> Move {
+        let dims = dims.as_ivec3();
+        let weights = WeightedIndex::new([
+            self.uniform,
+            self.near,
+            self.close_index,
+            self.cache_line,
+            self.sub_box,
+        ])
+        .expect("at least one swap proposal weight must be positive");
+
+        match weights.sample(rng) {
+            0 => random_swap(IVec3::ZERO, dims, rng),
+            1 => {
+                let a = random_cell(IVec3::ZERO, dims, rng);
+                let min = (a - self.radius).max(IVec3::ZERO);
+                let max = (a + self.radius + 1).min(dims);
+                swap_with(a, min, max, rng)
+            }
+            2 => {
+                let a = rng.random_range(0..order.len());
+                let min = a.saturating_sub(self.index_window);
+                let max = (a + self.index_window + 1).min(order.len());
+                swap_indices(order, a, min, max, rng)
+            }
+            3 => {
+                let a = rng.random_range(0..order.len());
+                let line = a / LINE_VOXELS;
+                let min = line.saturating_sub(1) * LINE_VOXELS;
+                let max = ((line + 2) * LINE_VOXELS).min(order.len());
+                swap_indices(order, a, min, max, rng)
+            }
+            _ => random_swap(self.box_min.max(IVec3::ZERO), self.box_max.min(dims), rng),
+        }
+        // degenerate regions (e.g. an empty sub-box) fall back to a uniform swap
+        .unwrap_or_else(|| random_swap(IVec3::ZERO, dims, rng).unwrap())
+    }
+}
+
+fn random_cell(min: IVec3, max: IVec3, rng: &mut impl Rng) -> IVec3 {
+    IVec3::new(
+        rng.random_range(min.x..max.x),
+        rng.random_range(min.y..max.y),
+        rng.random_range(min.z..max.z),
+    )
+}
+
+/// Swap two distinct cells picked uniformly from `min..max`.
+fn random_swap(min: IVec3, max: IVec3, rng: &mut impl Rng) -> Option<Move> {
+    if !max.cmpgt(min).all() {
+        return None;
+    }
+
+    swap_with(random_cell(min, max, rng), min, max, rng)
+}
+
+/// Swap `a` with a distinct cell picked uniformly from `min..max`.
+fn swap_with(a: IVec3, min: IVec3, max: IVec3, rng: &mut impl Rng) -> Option<Move> {
+    if (max - min).element_product() < 2 {
+        return None;
+    }
+
+    let b = loop {
+        let b = random_cell(min, max, rng);
+        if b != a {
+            break b;
+        }
+    };
+
+    Some(Move::Swap(a, b))
+}
+
+/// Swap the cell at index `a` with the cell at a distinct index picked uniformly from `min..max`.
+fn swap_indices(
+    order: &[U8Vec3],
+    a: usize,
+    min: usize,
+    max: usize,
+    rng: &mut impl Rng,
+) -> Option<Move> {
+    if max - min < 2 {
+        return None;
+    }
+
+    let b = loop {
+        let b = rng.random_range(min..max);
+        if b != a {
+            break b;
+        }
+    };
+
+    Some(Move::Swap(order[a].as_ivec3(), order[b].as_ivec3()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sub_box_is_clamped_to_the_layout() {
+        let proposals = SwapProposals {
+            uniform: 0.0,
+            near: 0.0,
+            close_index: 0.0,
+            cache_line: 0.0,
+            sub_box: 1.0,
+            box_min: IVec3::splat(-3),
+            box_max: IVec3::splat(100),
+            ..default()
+        };
+        let mut layout = Layout::new_box(UVec3::splat(4), crate::curves::Curve::Morton);
+        let order = layout.order();
+        let mut rng = rand::rng();
+        for _ in 0..1000 {
+            let mv = proposals.sample(&order, layout.dims(), &mut rng);
+            let Move::Swap(a, b) = mv else {
+                panic!("{:?} is not a swap", mv);
+            };
+            assert!(layout.contains(a) && layout.contains(b));
+            mv.apply(&mut layout);
+        }
+    }
+
+    /// Samples `proposals` many times on an 8^3 layout and checks every swap against `close`.
+    fn check_proposals(proposals: SwapProposals, close: impl Fn(&Layout, IVec3, IVec3) -> bool) {
+        let layout = Layout::new_box(UVec3::splat(8), crate::curves::Curve::Morton);
+        let order = layout.order();
+        let mut rng = rand::rng();
+        for _ in 0..10_000 {
+            let mv = proposals.sample(&order, layout.dims(), &mut rng);
+            let Move::Swap(a, b) = mv else {
+                panic!("{:?} is not a swap", mv);
+            };
+            assert_ne!(a, b);
+            assert!(layout.contains(a) && layout.contains(b));
+            assert!(close(&layout, a, b), "{:?}", mv);
+        }
+    }
+
+    fn only(near: f32, close_index: f32, cache_line: f32) -> SwapProposals {
+        SwapProposals {
+            uniform: 0.0,
+            near,
+            close_index,
+            cache_line,
+            ..default()
+        }
+    }
+
+    #[test]
+    fn near_swaps_stay_within_the_radius() {
+        let proposals = SwapProposals {
+            radius: 1,
+            ..only(1.0, 0.0, 0.0)
+        };
+        check_proposals(proposals, |_, a, b| (a - b).abs().max_element() <= 1);
+    }
+
+    #[test]
+    fn close_index_swaps_stay_within_the_window() {
+        let proposals = SwapProposals {
+            index_window: 5,
+            ..only(0.0, 1.0, 0.0)
+        };
+        check_proposals(proposals, |layout, a, b| {
+            layout.position(a).abs_diff(layout.position(b)) <= 5
+        });
+    }
+
+    #[test]
+    fn cache_line_swaps_stay_within_adjacent_lines() {
+        check_proposals(only(0.0, 0.0, 1.0), |layout, a, b| {
+            let line = |cell| layout.position(cell) / LINE_VOXELS;
+            line(a).abs_diff(line(b)) <= 1
+        });
+    }
+
+    fn moves() -> [Move; 7] {
+        [
+            Move::Swap(IVec3::new(1, 2, 3), IVec3::new(7, 0, 5)),
+            Move::Reverse { start: 5, end: 40 },
+            Move::Relocate {
+                start: 10,
+                len: 7,
+                to: 30,
+            },
+            Move::Relocate {
+                start: 30,
+                len: 7,
+                to: 2,
+            },
+            Move::Relocate {
+                start: 4,
+                len: 3,
+                to: 4,
+            },
+            Move::Rotate {
+                start: 3,
+                end: 20,
+                shift: 5,
+            },
+            Move::Rotate {
+                start: 0,
+                end: 63,
+                shift: 64,
+            },
+        ]
+    }
+
+    #[test]
+    fn inverse_restores_the_layout() {
+        let original = Layout::new_box(UVec3::splat(8), crate::curves::Curve::Hilbert);
+        for mv in moves() {
+            let mut layout = original.clone();
+            mv.apply(&mut layout);
+            mv.inverse().apply(&mut layout);
+            assert_eq!(layout.order(), original.order(), "{:?}", mv);
+        }
+    }
+
+    #[test]
+    fn relocate_moves_the_run() {
+        let mut layout = Layout::new_box(UVec3::splat(4), crate::curves::Curve::Linear);
+        let before = layout.order();
+        let mut order = before.clone();
+        Move::Relocate {
+            start: 2,
+            len: 3,
+            to: 6,
+        }
+        .apply_with_order(&mut layout, &mut order);
+        assert_eq!(order, layout.order());
+        assert_eq!(order[6..9], before[2..5]);
+        assert_eq!(order[2..6], before[5..9]);
+    }
+
+    #[test]
+    fn order_stays_in_sync() {
+        let mut layout = Layout::new_box(UVec3::splat(8), crate::curves::Curve::Morton);
+        let mut order = layout.order();
+        let weights = MoveWeights {
+            proposals: SwapProposals::mixture(),
+            ..default()
+        };
+        let mut rng = rand::rng();
+        for _ in 0..500 {
+            let mv = weights.sample(&order, layout.dims(), &mut rng).unwrap();
+            mv.apply_with_order(&mut layout, &mut order);
+            assert_eq!(order, layout.order(), "{:?}", mv);
+        }
+
+        for mv in moves() {
+            mv.apply_with_order(&mut layout, &mut order);
+            assert_eq!(order, layout.order(), "{:?}", mv);
+        }
+    }
+
+    #[test]
+    fn orbit_sampling_gives_up() {
+        // under the full group only opposite corners of a 2^3 cube can swap, none share a face
+        let weights = MoveWeights {
+            proposals: SwapProposals {
+                uniform: 0.0,
+                near: 0.0,
+                close_index: 0.0,
+                cache_line: 0.0,
+                sub_box: 1.0,
+                box_min: IVec3::ZERO,
+                box_max: IVec3::new(2, 2, 1),
+                ..default()
+            },
+            ..MoveWeights::swaps()
+        };
+        let group = SymmetryGroup::named("full").unwrap();
+        let layout = Layout::new_box(UVec3::splat(2), crate::curves::Curve::Linear);
+        let mut rng = rand::rng();
+        let orbit = weights.sample_orbit(&layout.order(), layout.dims(), &group, &mut rng);
+        assert_eq!(orbit, None);
+
+        let opposite =
+            MoveWeights::swaps().sample_orbit(&layout.order(), layout.dims(), &group, &mut rng);
+        let Some([Move::Swap(a, b), ..]) = opposite.as_deref() else {
+            panic!("no orbit swap on a 2^3 cube");
+        };
+        assert_eq!(*a + *b, IVec3::ONE);
+    }
+
+    #[test]
+    fn nothing_to_sample_below_two_cells() {
+        let weights = MoveWeights::default();
+        let mut rng = rand::rng();
+        let single = Layout::new_box(UVec3::ONE, crate::curves::Curve::Linear);
+        assert_eq!(
+            weights.sample(&single.order(), single.dims(), &mut rng),
+            None
+        );
+        assert_eq!(weights.sample(&[], UVec3::ZERO, &mut rng), None);
+    }
+}
@@ -0,0 +1,305 @@
+use std::io::{self, prelude::*};
+
+use rand::Rng;
+
+use crate::{LINE_VOXELS, Layout, VOXEL_BYTES, cost::Cost, moves::MoveWeights, stencil::Stencil};
+
+// Multi-objective search: the layouts that are good for several stencils and line sizes at once
+// don't have a single best, so instead of a best layout the search keeps the Pareto front, every
+// layout no other one beats on all criteria at once.
+
+/// One axis of the front, the line crossings of a stencil for a line size.
+#[derive(Debug, Clone)]
+pub struct Criterion {
+    /// `<stencil>:<line bytes>`, e.g. `faces:64`.
+    pub name: String,
+    pub cost: Cost,
+}
+
+impl Criterion {
+    /// Parses `<stencil>:<line bytes>`, the stencil `faces` for the 6 face neighbors of meshing or
+    /// `moore` for all 26 neighbors of AO.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let (stencil, bytes) = name.split_once(':')?;
+        let stencil = match stencil {
+            "faces" => Stencil::von_neumann(),
+            "moore" => Stencil::moore(),
+            _ => return None,
+        };
+        let bytes: usize = bytes.parse().ok()?;
+        if bytes < VOXEL_BYTES || !bytes.is_multiple_of(VOXEL_BYTES) {
+            return None;
+        }
+        Some(Self {
+            name: name.to_string(),
+            cost: Cost::Lines {
+                stencil,
+                line_voxels: bytes / VOXEL_BYTES,
+            },
+        })
+    }
+
+    /// Meshing and AO, each for 64 and 128 byte lines.
+    pub fn defaults() -> Vec<Self> {
+        let line_bytes = LINE_VOXELS * VOXEL_BYTES;
+        ["faces", "moore"]
+            .into_iter()
+            .flat_map(|stencil| [line_bytes, 2 * line_bytes].map(|bytes| (stencil, bytes)))
+            .map(|(stencil, bytes)| Self::from_name(&format!("{}:{}", stencil, bytes)).unwrap())
+            .collect()
+    }
+}
+
+/// Scores of `layout` under each criterion.
+pub fn evaluate(criteria: &[Criterion], layout: &Layout) -> Vec<usize> {
+    criteria
+        .iter()
+        .map(|criterion| criterion.cost.evaluate(layout))
+        .collect()
+}
+
+/// `a` is at least as good as `b` everywhere and better somewhere.
+pub fn dominates(a: &[usize], b: &[usize]) -> bool {
+    a.iter().zip(b).all(|(a, b)| a <= b) && a.iter().zip(b).any(|(a, b)| a < b)
+}
+
+/// The non-dominated layouts found so far, at most `capacity` of them.
+#[derive(Clone)]
+pub struct ParetoArchive {
+    pub entries: Vec<(Vec<usize>, Layout)>,
+    pub capacity: usize,
+}
+
+impl ParetoArchive {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            capacity,
+        }
+    }
+
+    /// Adds `layout` unless an entry dominates it, dropping the entries it dominates. A tie
+    /// replaces the entry, so like the single objective search the front can drift along
+    /// plateaus. Past the capacity the most crowded entry goes, so the front stays spread out.
+    pub fn insert(&mut self, scores: Vec<usize>, layout: Layout) -> bool {
+        if let Some(tie) = self.entries.iter_mut().find(|(entry, _)| *entry == scores) {
+            tie.1 = layout;
+            return true;
+        }
+        if self
+            .entries
+            .iter()
+            .any(|(entry, _)| dominates(entry, &scores))
+        {
+            return false;
+        }
+        self.entries.retain(|(entry, _)| !dominates(&scores, entry));
+        self.entries.push((scores, layout));
+
+        if self.entries.len() > self.capacity {
+            let crowded = (0..self.entries.len() - 1)
+                .min_by(|&a, &b| self.crowding(a).total_cmp(&self.crowding(b)))
+                .unwrap();
+            self.entries.swap_remove(crowded);
+        }
+        true
+    }
+
+    /// Distance of entry `index` to its nearest other entry, each criterion scaled by its range.
+    fn crowding(&self, index: usize) -> f64 {
+        let criteria = self.entries[index].0.len();
+        let ranges: Vec<f64> = (0..criteria)
+            .map(|criterion| {
+                let values = self.entries.iter().map(|(scores, _)| scores[criterion]);
+                let (min, max) = (values.clone().min().unwrap(), values.max().unwrap());
+                (max - min).max(1) as f64
+            })
+            .collect();
+        let (scores, _) = &self.entries[index];
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|&(other, _)| other != index)
+            .map(|(_, (other, _))| {
+                scores
+                    .iter()
+                    .zip(other)
+                    .zip(ranges.iter())
+                    .map(|((&a, &b), range)| (a.abs_diff(b) as f64 / range).powi(2))
+                    .sum::<f64>()
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    /// Sorts the front by the first criterion, then the next, for stable tables and file names.
+    pub fn sort(&mut self) {
+        self.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    /// Tab separated table of the front, a row per layout with its file name and scores.
+    pub fn write_table(&self, criteria: &[Criterion], out: &mut impl Write) -> io::Result<()> {
+        let names: Vec<&str> = criteria.iter().map(|c| c.name.as_str()).collect();
+        writeln!(out, "layout\t{}", names.join("\t"))?;
+        for (index, (scores, layout)) in self.entries.iter().enumerate() {
+            let scores: Vec<String> = scores.iter().map(usize::to_string).collect();
+            writeln!(out, "{}\t{}", file_name(index, layout), scores.join("\t"))?;
+        }
+        Ok(())
+    }
+
+    /// Writes every layout of the front and `front.tsv` with their scores into `dir`.
+    pub fn save(&self, criteria: &[Criterion], dir: &str) -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        for (index, (_, layout)) in self.entries.iter().enumerate() {
+            let buffer = serde_yml::to_string(layout).map_err(io::Error::other)?;
+            std::fs::write(format!("{}/{}", dir, file_name(index, layout)), buffer)?;
+        }
+        let mut table = io::BufWriter::new(std::fs::File::create(format!("{}/front.tsv", dir))?);
+        self.write_table(criteria, &mut table)?;
+        table.flush()
+    }
+}
+
+fn file_name(index: usize, layout: &Layout) -> String {
+    format!("layout-pareto-{}-{:02}.yml", layout.size_name(), index)
+}
+
+/// `pareto [iterations] [<stencil>:<line bytes>]... [layout yml]... [out dir]`
+///
+/// Searches for the Pareto front of the criteria, by default meshing and AO for 64 and 128 byte
+/// lines, starting from the given layouts, e.g. a saved front, or else the baselines. Each step
+/// perturbs a random layout of the front and keeps the result if nothing on the front dominates
+/// it. Prints the front as a table and saves it into the out dir, if given. Only the first
+/// argument can be the iterations, so any other is free to be a numbered out dir.
+pub fn run(args: &[String]) {
+    const CAPACITY: usize = 64;
+    const MAX_MOVES: usize = 2;
+    const PROGRESS: usize = 1_000;
+
+    let (iterations, args): (usize, _) = match args.first().and_then(|arg| arg.parse().ok()) {
+        Some(iterations) => (iterations, &args[1..]),
+        None => (10_000, args),
+    };
+    let mut criteria = Vec::new();
+    let mut seeds = Vec::new();
+    let mut dir = None;
+    for arg in args {
+        if arg.contains(':') {
+            match Criterion::from_name(arg) {
+                Some(criterion) => criteria.push(criterion),
+                None => {
+                    println!(
+                        "invalid criterion {:?}, expected <faces|moore>:<line bytes>",
+                        arg
+                    );
+                    return;
+                }
+            }
+        } else if arg.ends_with(".yml") {
+            match Layout::read(arg) {
+                Ok(layout) => seeds.push(layout),
+                Err(err) => {
+                    println!("could not read {:?}: {}", arg, err);
+                    return;
+                }
+            }
+        } else {
+            dir = Some(arg);
+        }
+    }
+    if seeds.is_empty() {
+        seeds = crate::named_baselines()
+            .into_iter()
+            .map(|(_, layout)| layout)
+            .collect();
+    }
+    if criteria.is_empty() {
+        criteria = Criterion::defaults();
+    }
+
+    let mut archive = ParetoArchive::new(CAPACITY);
+    for layout in seeds {
+        archive.insert(evaluate(&criteria, &layout), layout);
+    }
+
+    // small steps, larger moves almost never land next to the front
+    let moves = MoveWeights::swaps();
+    let mut rng = rand::rng();
+    let mut accepted = 0;
+    for iteration in 0..iterations {
+        if iteration.is_multiple_of(PROGRESS) {
+            println!(
+                "iteration {}: {} layouts on the front, {} accepted",
+                iteration,
+                archive.entries.len(),
+                accepted
+            );
+        }
+
+        let mut layout = archive.entries[rng.random_range(0..archive.entries.len())]
+            .1
+            .clone();
+        let dims = layout.dims();
+        let mut order = layout.order();
+        for _ in 0..rng.random_range(1..=MAX_MOVES) {
+            let Some(mv) = moves.sample(&order, dims, &mut rng) else {
+                break;
+            };
+            mv.apply_with_order(&mut layout, &mut order);
+        }
+        if archive.insert(evaluate(&criteria, &layout), layout) {
+            accepted += 1;
+        }
+    }
+
+    archive.sort();
+    archive.write_table(&criteria, &mut io::stdout()).unwrap();
+    if let Some(dir) = dir {
+        match archive.save(&criteria, dir) {
+            Ok(()) => println!("saved {} layouts to {:?}", archive.entries.len(), dir),
+            Err(err) => println!("could not save to {:?}: {}", dir, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_keeps_only_non_dominated() {
+        let layout = Layout::new_linear_2d(4);
+        let mut archive = ParetoArchive::new(3);
+        assert!(archive.insert(vec![5, 5], layout.clone()));
+        assert!(archive.insert(vec![3, 8], layout.clone()));
+        assert!(!archive.insert(vec![5, 6], layout.clone()));
+        assert!(archive.insert(vec![3, 8], layout.clone()));
+        assert_eq!(archive.entries.len(), 2);
+
+        // dominates the first entry
+        assert!(archive.insert(vec![4, 4], layout.clone()));
+        let mut scores: Vec<Vec<usize>> = archive.entries.iter().map(|e| e.0.clone()).collect();
+        scores.sort();
+        assert_eq!(scores, vec![vec![3, 8], vec![4, 4]]);
+
+        // over capacity the entry closest to another goes, never the new one
+        assert!(archive.insert(vec![8, 1], layout.clone()));
+        assert!(archive.insert(vec![4, 3], layout.clone()));
+        assert!(archive.insert(vec![7, 2], layout));
+        assert_eq!(archive.entries.len(), 3);
+        assert!(archive.entries.iter().any(|e| e.0 == vec![7, 2]));
+    }
+
+    #[test]
+    fn default_criteria() {
+        let names: Vec<String> = Criterion::defaults().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, ["faces:64", "faces:128", "moore:64", "moore:128"]);
+        assert!(Criterion::from_name("moore:3").is_none());
+        assert!(Criterion::from_name("edges:64").is_none());
+
+        // 64 byte lines under the moore stencil are the usual line crossings
+        let layout = Layout::new_morton();
+        let moore = Criterion::from_name("moore:64").unwrap();
+        assert_eq!(moore.cost.evaluate(&layout), layout.line_crossings());
+    }
+}
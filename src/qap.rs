@@ -0,0 +1,213 @@
+use bevy::prelude::*;
+use std::io::{self, BufWriter, prelude::*};
+
+use crate::{LINE_VOXELS, Layout, WIDTH, stencil::Stencil};
+
+// Quadratic Assignment Problem export/import in the QAPLIB format, so layouts can be benchmarked
+// against external QAP solvers.
+//
+// Facilities are the cells, numbered in linear order, and locations are the indices. The flow
+// between two cells is 1 if they are neighbors, the distance between two indices is 1 if they are
+// at least a line apart. Minimizing `sum flow(i, j) * distance(p(i), p(j))` over permutations `p`
+// is then exactly minimizing `Layout::heuristic_with` the stencil when lines are `LINE_VOXELS`.
+
+/// A QAP instance for the layouts of a volume.
+#[derive(Debug, Clone)]
+pub struct Qap {
+    pub dims: UVec3,
+    pub stencil: Stencil,
+    /// Indices at least this far apart are a unit of distance.
+    pub line_voxels: usize,
+}
+
+impl Qap {
+    /// The instance of [`Layout::heuristic`] for a volume of `dims`.
+    pub fn new(dims: UVec3) -> Self {
+        Self {
+            dims,
+            stencil: Stencil::for_dims(dims),
+            line_voxels: LINE_VOXELS,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.dims.element_product() as usize
+    }
+
+    fn cell(&self, facility: usize) -> IVec3 {
+        let (x, z) = (self.dims.x as usize, self.dims.z as usize);
+        IVec3::new(
+            (facility % x) as i32,
+            (facility / (x * z)) as i32,
+            (facility / x % z) as i32,
+        )
+    }
+
+    /// Inverse of `cell`, `None` outside the volume.
+    fn facility(&self, cell: IVec3) -> Option<usize> {
+        if cell.cmplt(IVec3::ZERO).any() || cell.cmpge(self.dims.as_ivec3()).any() {
+            return None;
+        }
+        let (cell, dims) = (cell.as_uvec3(), self.dims);
+        Some((cell.x + cell.z * dims.x + cell.y * dims.x * dims.z) as usize)
+    }
+
+    /// Writes the size, flow and distance matrices in the QAPLIB `.dat` format.
+    pub fn write_dat(&self, out: &mut impl Write) -> io::Result<()> {
+        let size = self.size();
+        writeln!(out, "{}", size)?;
+        writeln!(out)?;
+
+        for facility in 0..size {
+            let mut row = vec![0u8; size];
+            for &offset in self.stencil.0.iter() {
+                if let Some(neighbor) = self.facility(self.cell(facility) + offset)
+                    && offset != IVec3::ZERO
+                {
+                    row[neighbor] = 1;
+                }
+            }
+            write_row(out, &row)?;
+        }
+        writeln!(out)?;
+
+        for location in 0..size {
+            let row: Vec<u8> = (0..size)
+                .map(|other| (location.abs_diff(other) >= self.line_voxels) as u8)
+                .collect();
+            write_row(out, &row)?;
+        }
+        Ok(())
+    }
+
+    /// Parses a QAPLIB `.sln` solution (`n cost` followed by the 1-based location of every
+    /// facility) into its layout.
+    pub fn parse_solution(&self, solution: &str) -> io::Result<Layout> {
+        let mut numbers = solution.split_whitespace().map(|number| {
+            number
+                .parse::<usize>()
+                .map_err(|err| invalid(format!("{:?}: {}", number, err)))
+        });
+
+        let size = numbers.next().ok_or_else(|| invalid("empty solution".into()))??;
+        if size != self.size() {
+            return Err(invalid(format!(
+                "solution is for {} cells, expected {}",
+                size,
+                self.size()
+            )));
+        }
+        let _cost = numbers.next().ok_or_else(|| invalid("missing cost".into()))??;
+
+        let mut order = vec![None; size];
+        for facility in 0..size {
+            let location = numbers.next().ok_or_else(|| {
+                invalid(format!("missing location of facility {}", facility + 1))
+            })??;
+            if !(1..=size).contains(&location) || order[location - 1].is_some() {
+                return Err(invalid("solution is not a permutation".into()));
+            }
+            order[location - 1] = Some(self.cell(facility).as_u8vec3());
+        }
+
+        let order: Vec<_> = order.into_iter().flatten().collect();
+        Ok(Layout::from_order(&order))
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_row(file: &mut impl Write, row: &[u8]) -> io::Result<()> {
+    let row: Vec<String> = row.iter().map(|value| value.to_string()).collect();
+    writeln!(file, "{}", row.join(" "))
+}
+
+/// `qap export <dat> [size] [line]` or `qap import <sln> <layout yml> [size]`, for a cube of
+/// `size`, by default the chunk.
+pub fn run(args: &[String]) {
+    let size = |at: usize| {
+        args.get(at)
+            .and_then(|arg| arg.parse::<u32>().ok())
+            .unwrap_or(WIDTH as u32)
+    };
+    match (args.first().map(String::as_str), args.get(1), args.get(2)) {
+        (Some("export"), Some(dat), _) => {
+            let mut qap = Qap::new(UVec3::splat(size(2)));
+            if let Some(line) = args.get(3).and_then(|arg| arg.parse().ok()) {
+                qap.line_voxels = line;
+            }
+            let mut file = BufWriter::new(std::fs::File::create(dat).unwrap());
+            qap.write_dat(&mut file).unwrap();
+            file.flush().unwrap();
+            println!(
+                "wrote {}^3 QAP instance with {} voxel lines to {:?}",
+                qap.dims.x, qap.line_voxels, dat
+            );
+        }
+        (Some("import"), Some(sln), Some(name)) => {
+            let qap = Qap::new(UVec3::splat(size(3)));
+            let layout = match std::fs::read_to_string(sln)
+                .and_then(|solution| qap.parse_solution(&solution))
+            {
+                Ok(layout) => layout,
+                Err(err) => {
+                    println!("could not read {:?}: {}", sln, err);
+                    return;
+                }
+            };
+            println!("imported heuristic: {:?}", layout.heuristic());
+
+            let layout_buffer = serde_yml::to_string(&layout).unwrap();
+            let mut layout_file = std::fs::File::create(name).unwrap();
+            layout_file.write_all(layout_buffer.as_bytes()).unwrap();
+        }
+        _ => {
+            println!("usage: qap export <dat> [size] [line] | qap import <sln> <layout yml> [size]")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cost::Cost, curves::Curve};
+
+    /// Parses a `.dat` back into its flow and distance matrices.
+    fn parse_dat(dat: &str) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+        let mut numbers = dat.split_whitespace().map(|n| n.parse::<usize>().unwrap());
+        let size = numbers.next().unwrap();
+        let mut matrix = || {
+            (0..size)
+                .map(|_| numbers.by_ref().take(size).collect())
+                .collect::<Vec<Vec<usize>>>()
+        };
+        (matrix(), matrix())
+    }
+
+    #[test]
+    fn solution_round_trips_with_the_cost() {
+        let qap = Qap::new(UVec3::splat(4));
+        let mut dat = Vec::new();
+        qap.write_dat(&mut dat).unwrap();
+        let (flow, distance) = parse_dat(&String::from_utf8(dat).unwrap());
+
+        for curve in [Curve::Linear, Curve::Morton, Curve::Hilbert] {
+            let layout = Layout::new_box(qap.dims, curve);
+            let location: Vec<usize> = (0..qap.size())
+                .map(|facility| layout.get(qap.cell(facility)).unwrap())
+                .collect();
+            let cost: usize = (0..qap.size())
+                .flat_map(|a| (0..qap.size()).map(move |b| (a, b)))
+                .map(|(a, b)| flow[a][b] * distance[location[a]][location[b]])
+                .sum();
+            assert_eq!(cost, Cost::Heuristic.evaluate(&layout), "{:?}", curve);
+
+            let locations: Vec<String> = location.iter().map(|l| (l + 1).to_string()).collect();
+            let solution = format!("{} {}\n{}\n", qap.size(), cost, locations.join(" "));
+            let imported = qap.parse_solution(&solution).unwrap();
+            assert_eq!(imported.order(), layout.order());
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use bevy::prelude::*;
+
+/// Offsets read around each voxel (including the voxel itself), e.g. by meshing or AO.
+///
+/// Offsets that leave the layout are skipped, so a 3D stencil on a 2D layout only reaches the
+/// in-plane neighbors; the 2D stencils just avoid looking up the other planes at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stencil(pub Vec<IVec3>);
+
+impl Stencil {
+    fn filtered(flat: bool, keep: impl Fn(IVec3) -> bool) -> Self {
+        let depth = if flat { 0 } else { 1 };
+        let mut offsets = Vec::new();
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -depth..=depth {
+                    let offset = IVec3::new(x, y, z);
+                    if keep(offset) {
+                        offsets.push(offset);
+                    }
+                }
+            }
+        }
+        Self(offsets)
+    }
+
+    /// 3x3x3 neighborhood, the 26 face, edge and corner neighbors.
+    pub fn moore() -> Self {
+        Self::filtered(false, |_| true)
+    }
+
+    /// The 6 face neighbors.
+    pub fn von_neumann() -> Self {
+        Self::filtered(false, |offset| offset.abs().element_sum() <= 1)
+    }
+
+    /// 3x3 neighborhood in the z = 0 plane, the 8 edge and corner neighbors.
+    pub fn moore_2d() -> Self {
+        Self::filtered(true, |_| true)
+    }
+
+    /// The 4 edge neighbors in the z = 0 plane.
+    pub fn von_neumann_2d() -> Self {
+        Self::filtered(true, |offset| offset.abs().element_sum() <= 1)
+    }
+
+    /// Neighbors on either side along `direction`, e.g. a finite difference along one axis.
+    pub fn axis(direction: IVec3) -> Self {
+        Self(vec![-direction, IVec3::ZERO, direction])
+    }
+
+    /// The Moore stencil matching a layout of `dims`, 2D if it is a single plane.
+    pub fn for_dims(dims: UVec3) -> Self {
+        if dims.z <= 1 {
+            Self::moore_2d()
+        } else {
+            Self::moore()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stencil_sizes() {
+        assert_eq!(Stencil::moore().0.len(), 27);
+        assert_eq!(Stencil::von_neumann().0.len(), 7);
+        assert_eq!(Stencil::moore_2d().0.len(), 9);
+        assert_eq!(Stencil::von_neumann_2d().0.len(), 5);
+        for stencil in [Stencil::moore_2d(), Stencil::von_neumann_2d()] {
+            assert!(stencil.0.iter().all(|offset| offset.z == 0));
+        }
+    }
+}
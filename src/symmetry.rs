@@ -0,0 +1,349 @@
+use bevy::prelude::*;
+
+use crate::{LINE_VOXELS, Layout};
+
+/// One of the 48 symmetries of a cube: permute the axes, then mirror some of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symmetry {
+    /// Axis of the input each output axis is read from.
+    pub perm: [usize; 3],
+    /// Output axes mirrored after permuting.
+    pub flip: BVec3,
+}
+
+impl Symmetry {
+    pub const IDENTITY: Self = Self {
+        perm: [0, 1, 2],
+        flip: BVec3::FALSE,
+    };
+
+    /// All 48 symmetries, starting with the identity.
+    pub fn all() -> impl Iterator<Item = Self> {
+        const PERMS: [[usize; 3]; 6] = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+        PERMS.into_iter().flat_map(|perm| {
+            (0..8).map(move |bits| Self {
+                perm,
+                flip: BVec3::new(bits & 1 != 0, bits & 2 != 0, bits & 4 != 0),
+            })
+        })
+    }
+
+    /// Maps `point` inside a box of `dims` (the input dims) to the permuted and mirrored box.
+    pub fn apply(&self, point: IVec3, dims: IVec3) -> IVec3 {
+        let point = point.to_array();
+        let dims = dims.to_array();
+        let mut out = [0; 3];
+        for axis in 0..3 {
+            let value = point[self.perm[axis]];
+            out[axis] = match self.flip.test(axis) {
+                true => dims[self.perm[axis]] - 1 - value,
+                false => value,
+            };
+        }
+        IVec3::from_array(out)
+    }
+
+    /// Dims of the box `apply` maps a box of `dims` to.
+    pub fn apply_dims(&self, dims: IVec3) -> IVec3 {
+        let dims = dims.to_array();
+        IVec3::new(dims[self.perm[0]], dims[self.perm[1]], dims[self.perm[2]])
+    }
+
+    /// `self` followed by `next`.
+    pub fn then(&self, next: Self) -> Self {
+        let perm = next.perm.map(|axis| self.perm[axis]);
+        let mut flip = BVec3::FALSE;
+        for (axis, &from) in next.perm.iter().enumerate() {
+            flip.set(axis, next.flip.test(axis) != self.flip.test(from));
+        }
+        Self { perm, flip }
+    }
+
+    /// Mirror along `axis`.
+    pub fn mirror(axis: usize) -> Self {
+        let mut flip = BVec3::FALSE;
+        flip.set(axis, true);
+        Self {
+            flip,
+            ..Self::IDENTITY
+        }
+    }
+
+    /// Quarter turn around `axis`.
+    pub fn turn(axis: usize) -> Self {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut perm = [0; 3];
+        perm[axis] = axis;
+        perm[u] = v;
+        perm[v] = u;
+        let mut flip = BVec3::FALSE;
+        flip.set(v, true);
+        Self { perm, flip }
+    }
+
+    pub fn inverse(&self) -> Self {
+        let mut perm = [0; 3];
+        let mut flip = BVec3::FALSE;
+        for axis in 0..3 {
+            perm[self.perm[axis]] = axis;
+            flip.set(self.perm[axis], self.flip.test(axis));
+        }
+        Self { perm, flip }
+    }
+}
+
+/// Written as the input axis each output axis reads, e.g. `[z, -x, y]`.
+impl std::fmt::Display for Symmetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let axes: Vec<String> = (0..3)
+            .map(|axis| {
+                let sign = if self.flip.test(axis) { "-" } else { "" };
+                format!("{}{}", sign, ["x", "y", "z"][self.perm[axis]])
+            })
+            .collect();
+        write!(f, "[{}]", axes.join(", "))
+    }
+}
+
+/// A subgroup of the cube symmetries a layout is constrained to.
+///
+/// A layout is symmetric under the group when every symmetry maps each cache line onto a cache
+/// line. Indices are a bijection so they can't be invariant themselves, but the lines are what
+/// the cost looks at. Swapping cells together with all their images keeps this, see
+/// [`SymmetryGroup::orbit_swaps`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymmetryGroup(pub Vec<Symmetry>);
+
+impl SymmetryGroup {
+    /// Names accepted by [`SymmetryGroup::named`], smallest groups first.
+    pub const NAMES: [&'static str; 15] = [
+        "none", "mirror-x", "mirror-y", "mirror-z", "turn-x", "turn-y", "turn-z", "mirrors",
+        "square-x", "square-y", "square-z", "prism-x", "prism-y", "prism-z", "full",
+    ];
+
+    pub fn trivial() -> Self {
+        Self(vec![Symmetry::IDENTITY])
+    }
+
+    /// The smallest group containing `generators`.
+    pub fn generated(generators: &[Symmetry]) -> Self {
+        let mut elements = vec![Symmetry::IDENTITY];
+        let mut next = 0;
+        while next < elements.len() {
+            let element = elements[next];
+            for &generator in generators {
+                let product = element.then(generator);
+                if !elements.contains(&product) {
+                    elements.push(product);
+                }
+            }
+            next += 1;
+        }
+        Self(elements)
+    }
+
+    /// `mirror-<axis>` and `turn-<axis>` are generated by a single mirror or quarter turn,
+    /// `mirrors` by the three mirrors, `square-<axis>` is the symmetry of a square around the
+    /// axis, `prism-<axis>` additionally mirrors along it and `full` is all 48.
+    pub fn named(name: &str) -> Option<Self> {
+        let axis = |name: &str| match name {
+            "x" => Some(0),
+            "y" => Some(1),
+            "z" => Some(2),
+            _ => None,
+        };
+        let group = match name.split_once('-') {
+            None if name == "none" => Self::trivial(),
+            None if name == "mirrors" => Self::generated(&[0, 1, 2].map(Symmetry::mirror)),
+            None if name == "full" => Self(Symmetry::all().collect()),
+            Some(("mirror", a)) => Self::generated(&[Symmetry::mirror(axis(a)?)]),
+            Some(("turn", a)) => Self::generated(&[Symmetry::turn(axis(a)?)]),
+            Some(("square", a)) => {
+                let a = axis(a)?;
+                Self::generated(&[Symmetry::turn(a), Symmetry::mirror((a + 1) % 3)])
+            }
+            Some(("prism", a)) => {
+                let a = axis(a)?;
+                Self::generated(&[
+                    Symmetry::turn(a),
+                    Symmetry::mirror((a + 1) % 3),
+                    Symmetry::mirror(a),
+                ])
+            }
+            _ => return None,
+        };
+        Some(group)
+    }
+
+    pub fn is_trivial(&self) -> bool {
+        self.0.len() == 1
+    }
+
+    /// The swap of `a` and `b` together with its image under every symmetry, as disjoint swaps,
+    /// so the combined permutation commutes with the group. `None` if the images overlap in a
+    /// way no such permutation allows, e.g. `b` being a quarter turn of `a`, or `a` lying on a
+    /// mirror plane `b` doesn't.
+    pub fn orbit_swaps(&self, a: IVec3, b: IVec3, dims: IVec3) -> Option<Vec<(IVec3, IVec3)>> {
+        let mut image = bevy::platform::collections::HashMap::new();
+        for symmetry in self.0.iter() {
+            let (a, b) = (symmetry.apply(a, dims), symmetry.apply(b, dims));
+            for (from, to) in [(a, b), (b, a)] {
+                if *image.entry(from).or_insert(to) != to {
+                    return None;
+                }
+            }
+        }
+        Some(
+            image
+                .into_iter()
+                .filter(|(from, to)| from.to_array() < to.to_array())
+                .collect(),
+        )
+    }
+
+    /// Checks that every symmetry maps the layout onto itself and each of its cache lines onto a
+    /// cache line.
+    pub fn check(&self, layout: &Layout) -> Result<(), String> {
+        let dims = layout.dims().as_ivec3();
+        let order = layout.order();
+        for symmetry in self.0.iter() {
+            if symmetry.apply_dims(dims) != dims {
+                return Err(format!(
+                    "{} doesn't map a {} layout onto itself",
+                    symmetry,
+                    layout.size_name()
+                ));
+            }
+
+            let mut lines = vec![None; order.len().div_ceil(LINE_VOXELS)];
+            for (index, cell) in order.iter().enumerate() {
+                let image = layout.position(symmetry.apply(cell.as_ivec3(), dims));
+                let line = *lines[index / LINE_VOXELS].get_or_insert(image / LINE_VOXELS);
+                if line != image / LINE_VOXELS {
+                    return Err(format!(
+                        "{} splits line {} across lines {} and {}",
+                        symmetry,
+                        index / LINE_VOXELS,
+                        line,
+                        image / LINE_VOXELS
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{curves::Curve, moves::Move, stencil::Stencil};
+
+    #[test]
+    fn axis_orders_move_the_cost_between_axes() {
+        assert_eq!(
+            Layout::new_linear_with(Symmetry::IDENTITY).order(),
+            Layout::new_linear().order()
+        );
+        assert_eq!(
+            Layout::new_morton_with(Symmetry::IDENTITY).order(),
+            Layout::new_morton().order()
+        );
+
+        // linear runs along x, then z, so x neighbors are next to each other and y neighbors a
+        // whole plane apart
+        let (x, y) = (Stencil::axis(IVec3::X), Stencil::axis(IVec3::Y));
+        let y_pairs = 2 * 15 * 16 * 16;
+        let linear = Layout::new_linear();
+        assert_eq!(linear.heuristic_with(&x), 0);
+        assert_eq!(linear.heuristic_with(&y), y_pairs);
+
+        // reading x from y makes the rows run along y instead
+        let swapped = Layout::new_linear_with(Symmetry {
+            perm: [1, 0, 2],
+            flip: BVec3::FALSE,
+        });
+        assert_eq!(swapped.heuristic_with(&x), y_pairs);
+        assert_eq!(swapped.heuristic_with(&y), 0);
+    }
+
+    #[test]
+    fn groups_have_expected_orders() {
+        let orders = [1, 2, 2, 2, 4, 4, 4, 8, 8, 8, 8, 16, 16, 16, 48];
+        for (name, order) in SymmetryGroup::NAMES.into_iter().zip(orders) {
+            assert_eq!(
+                SymmetryGroup::named(name).unwrap().0.len(),
+                order,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn then_and_inverse_match_apply() {
+        let dims = IVec3::splat(5);
+        let point = IVec3::new(1, 2, 4);
+        for a in Symmetry::all() {
+            assert_eq!(a.inverse().apply(a.apply(point, dims), dims), point);
+            for b in Symmetry::all() {
+                assert_eq!(
+                    a.then(b).apply(point, dims),
+                    b.apply(a.apply(point, dims), dims)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn orbit_swaps_keep_symmetry() {
+        let group = SymmetryGroup::named("prism-z").unwrap();
+        let mut layout = Layout::new_morton();
+        assert!(group.check(&layout).is_ok());
+        assert!(
+            SymmetryGroup::named("full")
+                .unwrap()
+                .check(&layout)
+                .is_err()
+        );
+
+        let dims = layout.dims().as_ivec3();
+        for (a, b) in [
+            (IVec3::new(1, 0, 0), IVec3::new(5, 9, 3)),
+            (IVec3::new(2, 7, 1), IVec3::new(3, 7, 1)),
+            (IVec3::new(4, 5, 4), IVec3::new(12, 6, 4)),
+        ] {
+            for (a, b) in group.orbit_swaps(a, b, dims).unwrap() {
+                Move::Swap(a, b).apply(&mut layout);
+            }
+            assert!(group.check(&layout).is_ok());
+        }
+
+        // a quarter turn away, swapping along the orbit would need a 4-cycle
+        let a = IVec3::new(4, 5, 4);
+        let turned = Symmetry::turn(2).apply(a, dims);
+        assert_eq!(group.orbit_swaps(a, turned, dims), None);
+    }
+
+    #[test]
+    fn canonical_form_ignores_symmetries_and_reversal() {
+        let layout = Layout::new_bricked(UVec3::new(4, 2, 1), Curve::Snake, Curve::Hilbert);
+        let canonical = layout.canonicalize().order();
+        for (symmetry, reverse) in Symmetry::all()
+            .step_by(5)
+            .zip([false, true].into_iter().cycle())
+        {
+            let moved = layout.transformed(symmetry, reverse);
+            assert_eq!(moved.canonicalize().order(), canonical);
+            assert!(moved.equivalent(&layout));
+        }
+        assert!(!layout.equivalent(&Layout::new_morton()));
+    }
+}
@@ -0,0 +1,158 @@
+use bevy::prelude::*;
+use std::io::{self, BufWriter, prelude::*};
+
+use crate::{
+    Layout, VOXEL_BYTES,
+    cache::{CacheHierarchy, CacheModel, MODEL_USAGE, parse_model},
+};
+
+// Recorded voxel access sequences, e.g. dumped by meshing, raymarching or lighting, as a more
+// direct cost than the neighbor heuristic.
+//
+// A trace file is plain text with one access per line, `x y z` separated by whitespace. Empty
+// lines and everything after a `#` are ignored.
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace(pub Vec<IVec3>);
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Trace {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut accesses = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let coords: Vec<i32> = line
+                .split_whitespace()
+                .map(|coord| coord.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|err| invalid(format!("line {}: {}", number + 1, err)))?;
+            let [x, y, z] = coords[..] else {
+                return Err(invalid(format!("line {}: expected `x y z`", number + 1)));
+            };
+            accesses.push(IVec3::new(x, y, z));
+        }
+        Ok(Self(accesses))
+    }
+
+    pub fn read(path: &str) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(std::fs::File::create(path)?);
+        for access in self.0.iter() {
+            writeln!(file, "{} {} {}", access.x, access.y, access.z)?;
+        }
+        file.flush()
+    }
+
+    /// Replays the trace through cold caches, with voxels stored in `layout` order, returning
+    /// the misses per level. Accesses outside the layout (neighbor chunks) are skipped.
+    pub fn misses(&self, layout: &Layout, model: &CacheModel) -> Vec<usize> {
+        let mut caches = CacheHierarchy::new(model);
+        for &access in self.0.iter() {
+            if let Some(index) = layout.get(access) {
+                caches.access(index * VOXEL_BYTES);
+            }
+        }
+        caches.misses()
+    }
+}
+
+/// `trace <trace file> [cache options] [layout yml]...`, see [`parse_model`] for the options.
+///
+/// Prints the misses per level of replaying the trace against the baselines and the given layouts.
+pub fn run(args: &[String]) {
+    let usage = format!("usage: trace <trace file> {} [layout yml]...", MODEL_USAGE);
+    let Some((path, args)) = args.split_first() else {
+        println!("{}", usage);
+        return;
+    };
+    let trace = match Trace::read(path) {
+        Ok(trace) => trace,
+        Err(err) => {
+            println!("could not read {:?}: {}", path, err);
+            return;
+        }
+    };
+    let (model, layouts) = match parse_model(args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            println!("{}\n{}", err, usage);
+            return;
+        }
+    };
+
+    println!("{} accesses through {:?}", trace.0.len(), model);
+    for (name, layout) in [
+        ("linear", Layout::new_linear()),
+        ("morton", Layout::new_morton()),
+    ] {
+        println!("{}: {:?} misses", name, trace.misses(&layout, &model));
+    }
+    for name in layouts {
+        match Layout::read(name) {
+            Ok(layout) => println!("{}: {:?} misses", name, trace.misses(&layout, &model)),
+            Err(err) => println!("could not read {:?}: {}", name, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheConfig, Replacement};
+
+    #[test]
+    fn write_and_read_round_trip() {
+        let trace = Trace(vec![
+            IVec3::new(0, 1, 2),
+            IVec3::new(-1, 15, 3),
+            IVec3::ZERO,
+        ]);
+        let path = std::env::temp_dir().join(format!("trace-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        trace.write(path).unwrap();
+        let read = Trace::read(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(read.unwrap(), trace);
+
+        let parsed = Trace::parse("# header\n\n0 1 2  # first\n  -1 15 3\n0 0 0\n").unwrap();
+        assert_eq!(parsed, trace);
+        assert!(Trace::parse("0 1\n").is_err());
+        assert!(Trace::parse("0 1 x\n").is_err());
+    }
+
+    #[test]
+    fn misses_of_a_hand_built_sequence() {
+        // a line holds two rows of the linear layout, the access at x = -1 is in another chunk
+        let trace = Trace(vec![
+            IVec3::new(0, 0, 0),
+            IVec3::new(15, 0, 1),
+            IVec3::new(0, 0, 2),
+            IVec3::new(-1, 0, 0),
+            IVec3::new(1, 0, 0),
+        ]);
+        let layout = Layout::new_linear();
+        assert_eq!(trace.misses(&layout, &CacheModel::default()), vec![2]);
+
+        // a single line cache evicts the first line before it is used again
+        let tiny = CacheModel {
+            levels: vec![CacheConfig {
+                size: 64,
+                line: 64,
+                ways: 1,
+                replacement: Replacement::Lru,
+            }],
+            ..default()
+        };
+        assert_eq!(trace.misses(&layout, &tiny), vec![3]);
+    }
+}